@@ -1,6 +1,8 @@
 #import bevy_pbr::{
+    pbr_bindings,
     pbr_functions::alpha_discard,
     pbr_fragment::pbr_input_from_standard_material,
+    pbr_types::STANDARD_MATERIAL_FLAGS_BASE_COLOR_TEXTURE_BIT,
 }
 
 #ifdef PREPASS_PIPELINE
@@ -16,6 +18,60 @@
 }
 #endif
 
+struct GroundMaterial {
+    texture_scale: f32,
+    blend_sharpness: f32,
+    flags: u32,
+}
+
+const GROUND_ALBEDO_BIT: u32 = 1u;
+const GROUND_NORMAL_BIT: u32 = 2u;
+const GROUND_ROUGHNESS_BIT: u32 = 4u;
+
+@group(2) @binding(100) var<uniform> ground_material: GroundMaterial;
+@group(2) @binding(101) var albedo_textures: texture_2d_array<f32>;
+@group(2) @binding(102) var ground_sampler: sampler;
+@group(2) @binding(103) var normal_textures: texture_2d_array<f32>;
+@group(2) @binding(104) var roughness_textures: texture_2d_array<f32>;
+
+// Projections are taken along x (zy), y (xz) and z (xy), the same axes as the uvs of the mesh.
+// The texture arrays blend all three from the world position, the textures of the base material
+// are sampled with the uvs and tangents of the mesh
+fn triplanar_weights(normal: vec3<f32>) -> vec3<f32> {
+    let weights = pow(abs(normal), vec3<f32>(ground_material.blend_sharpness));
+    return weights / (weights.x + weights.y + weights.z);
+}
+
+fn sample_triplanar(
+    textures: texture_2d_array<f32>,
+    pos: vec3<f32>,
+    weights: vec3<f32>,
+    layer: u32,
+) -> vec4<f32> {
+    let clamped_layer = min(layer, textureNumLayers(textures) - 1u);
+    let x = textureSample(textures, ground_sampler, pos.zy, clamped_layer);
+    let y = textureSample(textures, ground_sampler, pos.xz, clamped_layer);
+    let z = textureSample(textures, ground_sampler, pos.xy, clamped_layer);
+    return x * weights.x + y * weights.y + z * weights.z;
+}
+
+// Whiteout blend of the three tangent space normal maps
+fn sample_triplanar_normal(
+    pos: vec3<f32>,
+    normal: vec3<f32>,
+    weights: vec3<f32>,
+    layer: u32,
+) -> vec3<f32> {
+    let clamped_layer = min(layer, textureNumLayers(normal_textures) - 1u);
+    var x = textureSample(normal_textures, ground_sampler, pos.zy, clamped_layer).xyz * 2. - 1.;
+    var y = textureSample(normal_textures, ground_sampler, pos.xz, clamped_layer).xyz * 2. - 1.;
+    var z = textureSample(normal_textures, ground_sampler, pos.xy, clamped_layer).xyz * 2. - 1.;
+    x = vec3<f32>(x.xy + normal.zy, abs(x.z) * normal.x);
+    y = vec3<f32>(y.xy + normal.xz, abs(y.z) * normal.y);
+    z = vec3<f32>(z.xy + normal.xy, abs(z.z) * normal.z);
+    return normalize(x.zyx * weights.x + y.xzy * weights.y + z.xyz * weights.z);
+}
+
 fn csin(x: f32) -> f32 {
     return sin(x) + (sin(x * 2) / 2.) + (sin(x * 4.) / 4.);
 }
@@ -32,12 +88,40 @@ fn fragment(
     var pbr_input = pbr_input_from_standard_material(in, is_front);
 
     var color =  vec4<f32>(1., 0.5, 0.5, 1.);
+    var layer = 1u;
     if in.world_position.y > -0.15 + height_noise(in.world_position.x, in.world_position.z, 0.1, 0.5) {
        color = vec4<f32>(0.1, 1., 0.1, 1.);
+       layer = 0u;
+    }
+
+    let pos = in.world_position.xyz * ground_material.texture_scale;
+    let weights = triplanar_weights(in.world_normal);
+    if (ground_material.flags & GROUND_ALBEDO_BIT) != 0u {
+        color = sample_triplanar(albedo_textures, pos, weights, layer);
+    } else {
+        // Without texture arrays the base material's textures are sampled with the uvs of the mesh,
+        // its normal and roughness maps are already part of the pbr input
+#ifdef VERTEX_UVS_A
+        if (pbr_bindings::material.flags & STANDARD_MATERIAL_FLAGS_BASE_COLOR_TEXTURE_BIT) != 0u {
+            color *= textureSample(pbr_bindings::base_color_texture, pbr_bindings::base_color_sampler, in.uv);
+        }
+#endif
+    }
+    if (ground_material.flags & GROUND_ROUGHNESS_BIT) != 0u {
+        pbr_input.material.perceptual_roughness = sample_triplanar(roughness_textures, pos, weights, layer).r;
+    }
+    if (ground_material.flags & GROUND_NORMAL_BIT) != 0u {
+        pbr_input.N = sample_triplanar_normal(pos, normalize(in.world_normal), weights, layer);
     }
 
     pbr_input.material.base_color = alpha_discard(pbr_input.material, color);
 
+#ifdef VERTEX_COLORS
+    // The vertex colors hold the ambient occlusion baked by the mesher
+    pbr_input.diffuse_occlusion *= in.color.r;
+    pbr_input.specular_occlusion *= in.color.r;
+#endif
+
 #ifdef PREPASS_PIPELINE
     let out = deferred_output(in, pbr_input);
 #else
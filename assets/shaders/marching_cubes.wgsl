@@ -1,8 +1,9 @@
-const INTERNAL_CHUNK_WIDTH: u32 = 31;
-const MAX_VERTICES_PER_VOXEL: u32 = 12;
+// Injected through shader defs by the `ChunkSettings` resource
+const INTERNAL_CHUNK_WIDTH: u32 = #{CHUNK_WIDTH}u;
+const MAX_VERTICES_PER_VOXEL: u32 = #{MAX_VERTICES_PER_CUBE}u;
 const CHUNK_WIDTH: u32 = INTERNAL_CHUNK_WIDTH + 2u;
-const INPUT_LENGTH = CHUNK_WIDTH * CHUNK_WIDTH * CHUNK_WIDTH / 32;
-const OUTPUT_LENGTH = INPUT_LENGTH * MAX_VERTICES_PER_VOXEL;
+const INPUT_LENGTH = (CHUNK_WIDTH * CHUNK_WIDTH * CHUNK_WIDTH + 31u) / 32u;
+const OUTPUT_LENGTH = CHUNK_WIDTH * CHUNK_WIDTH * CHUNK_WIDTH * MAX_VERTICES_PER_VOXEL;
 @group(0) @binding(0) var<storage, read_write> input_data: array<u32, INPUT_LENGTH>;
 @group(0) @binding(1) var<storage, read_write> output_data: array<vec4<f32>, OUTPUT_LENGTH>;
 
@@ -44,13 +45,17 @@ fn corner_index_to_coordinates(index: vec3<u32>, corner_index: u32) -> vec3<u32>
     return index + offset[corner_index];
 }
 
-@compute @workgroup_size(4, 4, 4)
+@compute @workgroup_size(#{WORKGROUP_SIZE}, #{WORKGROUP_SIZE}, #{WORKGROUP_SIZE})
 fn main(
     @builtin(global_invocation_id) index: vec3<u32>,
 ) {
     if output_data[0].w == -1. {
         output_data[0].w = 42.0;
     }
+    // The dispatch is rounded up to whole workgroups, skip the cubes outside of the input
+    if any(index >= vec3<u32>(CHUNK_WIDTH - 1u)) {
+        return;
+    }
     let coordinates = array<vec3<u32>, 8>(
         index,
         index + vec3<u32>(1, 0, 0),
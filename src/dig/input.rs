@@ -0,0 +1,537 @@
+//! # Input Module
+//!
+//! Actions the player's systems read instead of raw keys, buttons and mouse motion, so they can
+//! be rebound without touching those systems.
+//! Every player has its own [`ActionState`], filled from the devices of its [`InputSource`].
+//!
+//! Bindings are loaded from [`BINDINGS_PATH`], one action per line:
+//!
+//! ```text
+//! # Comments start with a hash
+//! jump = Key(Space), Gamepad(South)
+//! dig = Mouse(Left)
+//! look = Mouse(Motion), Stick(Right)
+//! stick_dead_zone = 0.2
+//! ```
+//!
+//! Bindings are named after the variants of [`KeyCode`], [`MouseButton`] and [`GamepadButton`].
+//! Looking around is bound to axes instead, see [`AxisBinding`].
+//! Actions missing from the file keep their default bindings.
+
+use bevy::{
+    input::{mouse::MouseMotion, InputSystem},
+    prelude::*,
+    reflect::DynamicEnum,
+    utils::{HashMap, HashSet},
+};
+
+/// Where the bindings are loaded from, relative to the working directory.
+pub const BINDINGS_PATH: &str = "assets/input_bindings.cfg";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Action {
+    Forward,
+    Back,
+    Left,
+    Right,
+    Jump,
+    Crouch,
+    /// Moves faster while held.
+    Sprint,
+    Dig,
+    Build,
+    /// Drops a sphere that can be pushed around.
+    Throw,
+    /// Drops a sphere that explodes on impact.
+    ThrowExplosive,
+    GrowBrush,
+    ShrinkBrush,
+    /// Switches flying on and off.
+    ToggleFly,
+    /// Switches flying through everything on and off.
+    ToggleNoclip,
+}
+
+impl Action {
+    pub const ALL: [Action; 15] = [
+        Action::Forward,
+        Action::Back,
+        Action::Left,
+        Action::Right,
+        Action::Jump,
+        Action::Crouch,
+        Action::Sprint,
+        Action::Dig,
+        Action::Build,
+        Action::Throw,
+        Action::ThrowExplosive,
+        Action::GrowBrush,
+        Action::ShrinkBrush,
+        Action::ToggleFly,
+        Action::ToggleNoclip,
+    ];
+
+    /// Name of the action in the bindings file.
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::Forward => "forward",
+            Action::Back => "back",
+            Action::Left => "left",
+            Action::Right => "right",
+            Action::Jump => "jump",
+            Action::Crouch => "crouch",
+            Action::Sprint => "sprint",
+            Action::Dig => "dig",
+            Action::Build => "build",
+            Action::Throw => "throw",
+            Action::ThrowExplosive => "throw_explosive",
+            Action::GrowBrush => "grow_brush",
+            Action::ShrinkBrush => "shrink_brush",
+            Action::ToggleFly => "toggle_fly",
+            Action::ToggleNoclip => "toggle_noclip",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Binding {
+    Key(KeyCode),
+    Mouse(MouseButton),
+    Gamepad(GamepadButton),
+}
+
+impl Binding {
+    /// Parses a binding written as `Key(KeyW)`, `Mouse(Left)` or `Gamepad(South)`.
+    pub fn parse(binding: &str) -> Option<Binding> {
+        let (device, button) = binding.trim().split_once('(')?;
+        let button = button.strip_suffix(')')?.trim();
+        match device.trim() {
+            "Key" => parse_variant(button).map(Binding::Key),
+            "Mouse" => parse_variant(button).map(Binding::Mouse),
+            "Gamepad" => parse_variant(button).map(Binding::Gamepad),
+            _ => None,
+        }
+    }
+}
+
+/// A source of two dimensional input, which looking around is bound to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AxisBinding {
+    MouseMotion,
+    LeftStick,
+    RightStick,
+}
+
+impl AxisBinding {
+    /// Parses an axis binding written as `Mouse(Motion)`, `Stick(Left)` or `Stick(Right)`.
+    pub fn parse(binding: &str) -> Option<AxisBinding> {
+        let (device, axis) = binding.trim().split_once('(')?;
+        match (device.trim(), axis.strip_suffix(')')?.trim()) {
+            ("Mouse", "Motion") => Some(AxisBinding::MouseMotion),
+            ("Stick", "Left") => Some(AxisBinding::LeftStick),
+            ("Stick", "Right") => Some(AxisBinding::RightStick),
+            _ => None,
+        }
+    }
+}
+
+/// Builds the unit variant of an enum from its name.
+fn parse_variant<T: FromReflect>(name: &str) -> Option<T> {
+    T::from_reflect(&DynamicEnum::new(name, ()))
+}
+
+/// What every action is bound to.
+#[derive(Resource, Debug, Clone)]
+pub struct InputBindings {
+    bindings: HashMap<Action, Vec<Binding>>,
+    /// Axes looking around is bound to.
+    pub look: Vec<AxisBinding>,
+    /// Look speed of a fully tilted stick, in mouse motion per frame.
+    pub stick_look_speed: f32,
+    /// Stick tilts shorter than this are ignored.
+    pub stick_dead_zone: f32,
+}
+
+impl Default for InputBindings {
+    fn default() -> Self {
+        let bindings = [
+            (Action::Forward, vec![Binding::Key(KeyCode::KeyW)]),
+            (Action::Back, vec![Binding::Key(KeyCode::KeyS)]),
+            (Action::Left, vec![Binding::Key(KeyCode::KeyA)]),
+            (Action::Right, vec![Binding::Key(KeyCode::KeyD)]),
+            (
+                Action::Jump,
+                vec![
+                    Binding::Key(KeyCode::Space),
+                    Binding::Gamepad(GamepadButton::South),
+                ],
+            ),
+            (
+                Action::Crouch,
+                vec![
+                    Binding::Key(KeyCode::ControlLeft),
+                    Binding::Gamepad(GamepadButton::East),
+                ],
+            ),
+            (
+                Action::Sprint,
+                vec![
+                    Binding::Key(KeyCode::ShiftLeft),
+                    Binding::Gamepad(GamepadButton::LeftThumb),
+                ],
+            ),
+            (
+                Action::Dig,
+                vec![
+                    Binding::Mouse(MouseButton::Left),
+                    Binding::Gamepad(GamepadButton::RightTrigger2),
+                ],
+            ),
+            (
+                Action::Build,
+                vec![
+                    Binding::Key(KeyCode::KeyB),
+                    Binding::Gamepad(GamepadButton::LeftTrigger2),
+                ],
+            ),
+            (
+                Action::Throw,
+                vec![
+                    Binding::Key(KeyCode::KeyR),
+                    Binding::Gamepad(GamepadButton::West),
+                ],
+            ),
+            (
+                Action::ThrowExplosive,
+                vec![
+                    Binding::Key(KeyCode::KeyT),
+                    Binding::Gamepad(GamepadButton::North),
+                ],
+            ),
+            (
+                Action::GrowBrush,
+                vec![
+                    Binding::Key(KeyCode::KeyE),
+                    Binding::Gamepad(GamepadButton::RightTrigger),
+                ],
+            ),
+            (
+                Action::ShrinkBrush,
+                vec![
+                    Binding::Key(KeyCode::KeyQ),
+                    Binding::Gamepad(GamepadButton::LeftTrigger),
+                ],
+            ),
+            (Action::ToggleFly, vec![Binding::Key(KeyCode::KeyF)]),
+            (Action::ToggleNoclip, vec![Binding::Key(KeyCode::KeyN)]),
+        ];
+        Self {
+            bindings: bindings.into_iter().collect(),
+            look: vec![AxisBinding::MouseMotion, AxisBinding::RightStick],
+            stick_look_speed: 20.,
+            stick_dead_zone: 0.15,
+        }
+    }
+}
+
+impl InputBindings {
+    pub fn get(&self, action: Action) -> &[Binding] {
+        self.bindings
+            .get(&action)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// Replaces the bindings of the action.
+    pub fn bind(&mut self, action: Action, bindings: Vec<Binding>) {
+        self.bindings.insert(action, bindings);
+    }
+
+    /// Loads the bindings file, falling back to the default bindings when it can't be read.
+    pub fn load(path: &str) -> InputBindings {
+        let mut bindings = InputBindings::default();
+        match std::fs::read_to_string(path) {
+            Ok(config) => bindings.apply_config(&config),
+            Err(err) => warn!("Using the default input bindings, couldn't read {path}: {err}"),
+        }
+        bindings
+    }
+
+    /// Overrides the bindings and settings found in the config, invalid lines are skipped.
+    pub fn apply_config(&mut self, config: &str) {
+        for (number, line) in config.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let Some((name, value)) = line.split_once('=') else {
+                warn!(
+                    "Input bindings line {}: expected `name = value`",
+                    number + 1
+                );
+                continue;
+            };
+            let (name, value) = (name.trim(), value.trim());
+            if let Some(action) = Action::ALL.into_iter().find(|action| action.name() == name) {
+                let bindings: Option<Vec<Binding>> = value
+                    .split(',')
+                    .filter(|binding| !binding.trim().is_empty())
+                    .map(Binding::parse)
+                    .collect();
+                match bindings {
+                    Some(bindings) => self.bind(action, bindings),
+                    None => warn!("Input bindings line {}: invalid binding", number + 1),
+                }
+                continue;
+            }
+            if name == "look" {
+                let look: Option<Vec<AxisBinding>> = value
+                    .split(',')
+                    .filter(|binding| !binding.trim().is_empty())
+                    .map(AxisBinding::parse)
+                    .collect();
+                match look {
+                    Some(look) => self.look = look,
+                    None => warn!("Input bindings line {}: invalid axis binding", number + 1),
+                }
+                continue;
+            }
+            let setting = match name {
+                "stick_look_speed" => &mut self.stick_look_speed,
+                "stick_dead_zone" => &mut self.stick_dead_zone,
+                _ => {
+                    warn!(
+                        "Input bindings line {}: unknown action `{name}`",
+                        number + 1
+                    );
+                    continue;
+                }
+            };
+            match value.parse() {
+                Ok(value) => *setting = value,
+                Err(_) => warn!("Input bindings line {}: expected a number", number + 1),
+            }
+        }
+    }
+}
+
+/// Where the actions of a player come from.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+#[require(ActionState)]
+pub enum InputSource {
+    /// The keyboard and mouse, along with every gamepad no other player owns.
+    KeyboardMouse,
+    /// A single gamepad entity.
+    Gamepad(Entity),
+    /// Another system, such as an AI, sets the actions through [`ActionState::set_pressed`].
+    External,
+}
+
+/// State of a player's actions this frame, gathered from its [`InputSource`].
+#[derive(Component, Debug, Default)]
+pub struct ActionState {
+    pressed: HashSet<Action>,
+    just_pressed: HashSet<Action>,
+    /// Movement input, x to the right and y forward, at most 1 long.
+    pub movement: Vec2,
+    /// How much to look around this frame, in mouse motion.
+    pub look: Vec2,
+}
+
+impl ActionState {
+    pub fn pressed(&self, action: Action) -> bool {
+        self.pressed.contains(&action)
+    }
+
+    pub fn just_pressed(&self, action: Action) -> bool {
+        self.just_pressed.contains(&action)
+    }
+
+    /// Holds or releases the action, for players whose actions don't come from devices.
+    pub fn set_pressed(&mut self, action: Action, pressed: bool) {
+        if !pressed {
+            self.pressed.remove(&action);
+        } else if self.pressed.insert(action) {
+            self.just_pressed.insert(action);
+        }
+    }
+}
+
+pub struct InputActionsPlugin;
+impl Plugin for InputActionsPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(InputBindings::load(BINDINGS_PATH))
+            .add_systems(PreUpdate, update_action_states.after(InputSystem));
+    }
+}
+
+fn update_action_states(
+    bindings: Res<InputBindings>,
+    keys: Res<ButtonInput<KeyCode>>,
+    mouse_buttons: Res<ButtonInput<MouseButton>>,
+    mut motion_r: EventReader<MouseMotion>,
+    gamepads_q: Query<(Entity, &Gamepad)>,
+    mut players_q: Query<(&InputSource, &mut ActionState)>,
+) {
+    let mouse_motion: Vec2 = motion_r.read().map(|motion| motion.delta).sum();
+    let owned: HashSet<Entity> = players_q
+        .iter()
+        .filter_map(|(source, _)| match source {
+            InputSource::Gamepad(gamepad) => Some(*gamepad),
+            _ => None,
+        })
+        .collect();
+    let dead_zone = |stick: Vec2| {
+        if stick.length() < bindings.stick_dead_zone {
+            Vec2::ZERO
+        } else {
+            stick
+        }
+    };
+
+    for (source, mut state) in players_q.iter_mut() {
+        state.just_pressed.clear();
+        let (keyboard_mouse, gamepads): (bool, Vec<&Gamepad>) = match source {
+            InputSource::External => continue,
+            InputSource::KeyboardMouse => (
+                true,
+                gamepads_q
+                    .iter()
+                    .filter(|(entity, _)| !owned.contains(entity))
+                    .map(|(_, gamepad)| gamepad)
+                    .collect(),
+            ),
+            InputSource::Gamepad(entity) => (
+                false,
+                gamepads_q
+                    .get(*entity)
+                    .map(|(_, gamepad)| gamepad)
+                    .into_iter()
+                    .collect(),
+            ),
+        };
+
+        state.pressed.clear();
+        for action in Action::ALL {
+            for binding in bindings.get(action) {
+                let (pressed, just_pressed) = match *binding {
+                    Binding::Key(key) if keyboard_mouse => {
+                        (keys.pressed(key), keys.just_pressed(key))
+                    }
+                    Binding::Mouse(button) if keyboard_mouse => (
+                        mouse_buttons.pressed(button),
+                        mouse_buttons.just_pressed(button),
+                    ),
+                    Binding::Gamepad(button) => (
+                        gamepads.iter().any(|gamepad| gamepad.pressed(button)),
+                        gamepads.iter().any(|gamepad| gamepad.just_pressed(button)),
+                    ),
+                    _ => (false, false),
+                };
+                if pressed {
+                    state.pressed.insert(action);
+                }
+                if just_pressed {
+                    state.just_pressed.insert(action);
+                }
+            }
+        }
+
+        let axis = |negative, positive| {
+            state.pressed(positive) as i32 as f32 - state.pressed(negative) as i32 as f32
+        };
+        let mut movement = Vec2::new(
+            axis(Action::Left, Action::Right),
+            axis(Action::Back, Action::Forward),
+        );
+        // Mouse motion goes down the screen, sticks go up
+        let stick_look =
+            |stick: Vec2| dead_zone(stick) * Vec2::new(1., -1.) * bindings.stick_look_speed;
+        let mut look = Vec2::ZERO;
+        for axis in bindings.look.iter() {
+            look += match axis {
+                AxisBinding::MouseMotion if keyboard_mouse => mouse_motion,
+                AxisBinding::MouseMotion => Vec2::ZERO,
+                AxisBinding::LeftStick => gamepads
+                    .iter()
+                    .map(|gamepad| stick_look(gamepad.left_stick()))
+                    .sum(),
+                AxisBinding::RightStick => gamepads
+                    .iter()
+                    .map(|gamepad| stick_look(gamepad.right_stick()))
+                    .sum(),
+            };
+        }
+        for gamepad in gamepads {
+            movement += dead_zone(gamepad.left_stick());
+        }
+        state.movement = movement.clamp_length_max(1.);
+        state.look = look;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bindings_parse_every_device() {
+        assert_eq!(
+            Binding::parse("Key(KeyW)"),
+            Some(Binding::Key(KeyCode::KeyW))
+        );
+        assert_eq!(
+            Binding::parse(" Mouse( Left ) "),
+            Some(Binding::Mouse(MouseButton::Left))
+        );
+        assert_eq!(
+            Binding::parse("Gamepad(South)"),
+            Some(Binding::Gamepad(GamepadButton::South))
+        );
+        assert_eq!(
+            AxisBinding::parse("Stick(Right)"),
+            Some(AxisBinding::RightStick)
+        );
+    }
+
+    #[test]
+    fn invalid_bindings_are_rejected() {
+        assert_eq!(Binding::parse("Key(NotAKey)"), None);
+        assert_eq!(Binding::parse("Joystick(South)"), None);
+        assert_eq!(Binding::parse("Key(KeyW"), None);
+        assert_eq!(Binding::parse("KeyW"), None);
+        assert_eq!(AxisBinding::parse("Stick(Middle)"), None);
+    }
+
+    #[test]
+    fn config_overrides_the_listed_actions_only() {
+        let mut bindings = InputBindings::default();
+        bindings.apply_config(
+            "# Comments and blank lines are skipped\n\
+             \n\
+             jump = Key(KeyJ), Gamepad(North) # Trailing comment\n\
+             build =\n\
+             dig = Mouse(Nope)\n\
+             look = Stick(Left)\n\
+             stick_dead_zone = 0.3\n\
+             stick_look_speed = fast\n\
+             unknown = Key(KeyU)\n\
+             not a binding\n",
+        );
+        assert_eq!(
+            bindings.get(Action::Jump),
+            [
+                Binding::Key(KeyCode::KeyJ),
+                Binding::Gamepad(GamepadButton::North)
+            ]
+        );
+        assert!(bindings.get(Action::Build).is_empty());
+        // Invalid values keep the defaults
+        let defaults = InputBindings::default();
+        assert_eq!(bindings.get(Action::Dig), defaults.get(Action::Dig));
+        assert_eq!(bindings.get(Action::Forward), defaults.get(Action::Forward));
+        assert_eq!(bindings.stick_look_speed, defaults.stick_look_speed);
+        assert_eq!(bindings.look, [AxisBinding::LeftStick]);
+        assert_eq!(bindings.stick_dead_zone, 0.3);
+    }
+}
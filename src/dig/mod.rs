@@ -1,15 +1,39 @@
 use bevy::prelude::*;
+use input::InputActionsPlugin;
 use player::DigPlayerPlugin;
 use terrain::DigTerrainPlugin;
 
-use crate::sky::SkyPlugin;
+use crate::{
+    generation::{ChunkSettings, DEFAULT_CHUNK_WIDTH},
+    sky::SkyPlugin,
+};
 
+pub mod input;
 pub mod player;
 pub mod terrain;
 
-pub struct DigPlugin;
+pub struct DigPlugin {
+    /// How many voxels a terrain chunk holds along each axis.
+    pub chunk_width: usize,
+}
+
+impl Default for DigPlugin {
+    fn default() -> Self {
+        Self {
+            chunk_width: DEFAULT_CHUNK_WIDTH,
+        }
+    }
+}
+
 impl Plugin for DigPlugin {
     fn build(&self, app: &mut App) {
-        app.add_plugins((DigPlayerPlugin, DigTerrainPlugin, SkyPlugin));
+        app.add_plugins((
+            InputActionsPlugin,
+            DigPlayerPlugin,
+            DigTerrainPlugin {
+                chunk_settings: ChunkSettings::new(self.chunk_width),
+            },
+            SkyPlugin,
+        ));
     }
 }
@@ -1,7 +1,9 @@
-use bevy::input::mouse::MouseMotion;
 use bevy::prelude::*;
+use bevy::render::camera::Viewport;
 use bevy::window::{CursorGrabMode, PrimaryWindow};
 
+use crate::dig::input::ActionState;
+
 pub struct FpsCameraPlugin;
 
 #[derive(Component)]
@@ -19,19 +21,23 @@ impl FpsCamera {
 impl Plugin for FpsCameraPlugin {
     fn build(&self, app: &mut App) {
         app.add_systems(Update, handle_fps_cameras)
-            .add_systems(Update, setup);
+            .add_systems(Update, (split_screen, setup).chain());
     }
 }
 
+/// Locks the cursor while any camera is looked through.
 fn setup(
-    query: Query<&Camera, (With<FpsCamera>, Changed<Camera>)>,
+    changed_q: Query<(), (With<FpsCamera>, Changed<Camera>)>,
+    cameras_q: Query<&Camera, With<FpsCamera>>,
     mut q_windows: Query<&mut Window, With<PrimaryWindow>>,
 ) {
-    let mut primary_window = q_windows.single_mut();
-    let Ok(camera) = query.get_single() else {
+    if changed_q.is_empty() {
+        return;
+    }
+    let Ok(mut primary_window) = q_windows.get_single_mut() else {
         return;
     };
-    if camera.is_active {
+    if cameras_q.iter().any(|camera| camera.is_active) {
         primary_window.cursor_options.grab_mode = CursorGrabMode::Locked;
         primary_window.cursor_options.visible = false;
     } else {
@@ -40,31 +46,76 @@ fn setup(
     }
 }
 
+/// Splits the window into a grid with a viewport for every active camera.
+fn split_screen(
+    q_windows: Query<&Window, With<PrimaryWindow>>,
+    mut cameras_q: Query<(Entity, &mut Camera), With<FpsCamera>>,
+) {
+    let Ok(window) = q_windows.get_single() else {
+        return;
+    };
+    let mut active: Vec<_> = cameras_q
+        .iter_mut()
+        .filter(|(_, camera)| camera.is_active)
+        .collect();
+    // Keep every player in the same spot of the screen from frame to frame
+    active.sort_by_key(|(entity, _)| *entity);
+
+    let count = active.len() as u32;
+    let columns = (count as f32).sqrt().ceil().max(1.) as u32;
+    let rows = count.div_ceil(columns).max(1);
+    let cell = window.physical_size() / UVec2::new(columns, rows);
+    let rect = |viewport: &Option<Viewport>| {
+        viewport
+            .as_ref()
+            .map(|viewport| (viewport.physical_position, viewport.physical_size))
+    };
+    for (i, (_, mut camera)) in active.into_iter().enumerate() {
+        let i = i as u32;
+        let viewport = (count > 1).then(|| Viewport {
+            physical_position: UVec2::new(i % columns, i / columns) * cell,
+            physical_size: cell.max(UVec2::ONE),
+            ..default()
+        });
+        if rect(&camera.viewport) != rect(&viewport) {
+            camera.viewport = viewport;
+        }
+        if camera.order != i as isize {
+            camera.order = i as isize;
+        }
+    }
+}
+
 fn handle_fps_cameras(
     mut query: Query<(Entity, &FpsCamera, Option<&Parent>)>,
-    mut motion_evr: EventReader<MouseMotion>,
+    actions_q: Query<&ActionState>,
     mut transform_query: Query<&mut Transform>,
     time: Res<Time>,
 ) {
     for (entity, free_cam, maybe_parent) in &mut query {
-        for ev in motion_evr.read() {
-            let rotation_dir = -ev.delta * free_cam.sensitivity * time.delta_secs();
-            if let Some(parent) = maybe_parent {
-                let Ok([mut transform, mut parent_transform]) =
-                    transform_query.get_many_mut([entity, parent.get()])
-                else {
-                    continue;
-                };
-                transform.rotate_axis(Dir3::X, rotation_dir.y);
-                parent_transform.rotate_axis(Dir3::Y, rotation_dir.x);
-            } else {
-                let Ok(mut transform) = transform_query.get_mut(entity) else {
-                    continue;
-                };
-                let right = transform.right();
-                transform.rotate_axis(right, rotation_dir.y);
-                transform.rotate_axis(Dir3::Y, rotation_dir.x);
-            }
+        // Cameras look with the actions of the player they belong to
+        let Ok(actions) = actions_q.get(maybe_parent.map_or(entity, Parent::get)) else {
+            continue;
+        };
+        if actions.look == Vec2::ZERO {
+            continue;
+        }
+        let rotation_dir = -actions.look * free_cam.sensitivity * time.delta_secs();
+        if let Some(parent) = maybe_parent {
+            let Ok([mut transform, mut parent_transform]) =
+                transform_query.get_many_mut([entity, parent.get()])
+            else {
+                continue;
+            };
+            transform.rotate_axis(Dir3::X, rotation_dir.y);
+            parent_transform.rotate_axis(Dir3::Y, rotation_dir.x);
+        } else {
+            let Ok(mut transform) = transform_query.get_mut(entity) else {
+                continue;
+            };
+            let right = transform.right();
+            transform.rotate_axis(right, rotation_dir.y);
+            transform.rotate_axis(Dir3::Y, rotation_dir.x);
         }
     }
 }
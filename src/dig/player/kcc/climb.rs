@@ -0,0 +1,195 @@
+//! # Climb Module
+//!
+//! Lets characters climb the walls too steep to walk on, so they can get out of the pits they
+//! dig:
+//!
+//! - Holding forward against a wall steeper than [`KCCSlope::max_slope_angle`] starts climbing,
+//!   until the stamina runs out or forward is released.
+//! - Climbing characters move along the plane of the wall, without gravity.
+//! - Ledges within reach are mantled over, pulling the character up and onto them.
+
+use avian3d::prelude::*;
+use bevy::prelude::*;
+
+use super::{
+    collision::{KCCCollision, KCCCollisionBackend},
+    movement::is_walkable,
+    KCCGrounded, KCCMovementMode, KCCSlope, KinematicCharacterController,
+};
+
+/// Distance kept between the character and what it mantles over.
+const MANTLE_EPSILON: f32 = 0.01;
+
+/// Component that lets the character climb steep walls and mantle over ledges.
+#[derive(Component, Reflect, Debug)]
+#[reflect(Component)]
+pub struct KCCClimb {
+    /// Whether the character should climb the wall in front of it, set by the input.
+    pub wants_climb: bool,
+    /// Normal of the wall being climbed, [`Vec3::ZERO`] when there is no climbable wall in front.
+    pub wall_normal: Vec3,
+    /// How far in front of the character walls are grabbed.
+    pub reach: f32,
+    /// Speed of the character along the wall.
+    pub climb_speed: f32,
+    /// Seconds of climbing left.
+    pub stamina: f32,
+    /// Seconds of climbing with full stamina.
+    pub max_stamina: f32,
+    /// Stamina regained per second while standing on the ground.
+    pub stamina_recovery: f32,
+    /// How far the character's center can be lifted to get over a ledge, so ledges up to this
+    /// high above its feet can be mantled over.
+    pub mantle_height: f32,
+    /// How far onto the ledge the character is pulled when mantling.
+    pub mantle_distance: f32,
+    /// Speed of the character while mantling.
+    pub mantle_speed: f32,
+    /// Points the character is moving through to get over a ledge, in order.
+    #[reflect(ignore)]
+    pub mantle_path: Vec<Vec3>,
+}
+
+impl Default for KCCClimb {
+    fn default() -> Self {
+        Self {
+            wants_climb: false,
+            wall_normal: Vec3::ZERO,
+            reach: 0.1,
+            climb_speed: 2.0,
+            stamina: 4.0,
+            max_stamina: 4.0,
+            stamina_recovery: 2.0,
+            mantle_height: 1.0,
+            mantle_distance: 0.5,
+            mantle_speed: 4.0,
+            mantle_path: Vec::new(),
+        }
+    }
+}
+
+impl KCCClimb {
+    /// Whether the character is on its way over a ledge.
+    pub fn is_mantling(&self) -> bool {
+        !self.mantle_path.is_empty()
+    }
+}
+
+/// Grabs the walls the characters push against, lets go of them and mantles over their top.
+pub fn update_climbing(
+    mut query: Query<(
+        Entity,
+        &mut KCCClimb,
+        &mut KCCMovementMode,
+        &mut KinematicCharacterController,
+        &mut Transform,
+        Option<&KCCSlope>,
+        Option<&KCCGrounded>,
+        Option<&KCCCollisionBackend>,
+    )>,
+    collision: KCCCollision,
+    time: Res<Time>,
+) {
+    let dt = time.delta_secs();
+    for (entity, mut climb, mut mode, mut controller, mut transform, slope, grounded, backend) in
+        query.iter_mut()
+    {
+        if climb.is_mantling() {
+            mantle(&mut climb, &mut controller, &mut transform, dt);
+            if !climb.is_mantling() {
+                *mode = KCCMovementMode::Walk;
+            }
+            continue;
+        }
+
+        if *mode == KCCMovementMode::Climb {
+            climb.stamina = (climb.stamina - dt).max(0.0);
+        } else if grounded.is_some_and(|grounded| grounded.grounded) {
+            climb.stamina = (climb.stamina + climb.stamina_recovery * dt).min(climb.max_stamina);
+        }
+        if !matches!(*mode, KCCMovementMode::Walk | KCCMovementMode::Climb) {
+            climb.wall_normal = Vec3::ZERO;
+            continue;
+        }
+
+        let cast = |origin: Vec3, direction: Dir3, distance: f32| {
+            collision.cast_shape(
+                backend.copied().unwrap_or_default(),
+                entity,
+                &controller.collider,
+                origin,
+                transform.rotation,
+                direction,
+                &ShapeCastConfig {
+                    ignore_origin_penetration: false,
+                    max_distance: distance,
+                    ..default()
+                },
+            )
+        };
+        let Ok(up) = Dir3::new(controller.up) else {
+            continue;
+        };
+        let Ok(forward) = Dir3::new(transform.forward().reject_from(*up)) else {
+            continue;
+        };
+
+        climb.wall_normal = cast(transform.translation, forward, climb.reach)
+            .filter(|hit| !is_walkable(slope, *up, hit.normal))
+            .map_or(Vec3::ZERO, |hit| hit.normal);
+        if climb.wall_normal == Vec3::ZERO || !climb.wants_climb {
+            if *mode == KCCMovementMode::Climb {
+                *mode = KCCMovementMode::Walk;
+            }
+            continue;
+        }
+
+        // Ledges within reach are mantled over, whether climbing or walking into them
+        let start = transform.translation;
+        let rise = cast(start, up, climb.mantle_height).map_or(climb.mantle_height, |hit| {
+            (hit.distance - MANTLE_EPSILON).max(0.0)
+        });
+        let raised = start + *up * rise;
+        let advanced = raised + *forward * climb.mantle_distance;
+        let landing = if cast(raised, forward, climb.mantle_distance).is_none() {
+            cast(advanced, -up, rise).filter(|hit| is_walkable(slope, *up, hit.normal))
+        } else {
+            None
+        };
+        if let Some(landing) = landing {
+            let target = advanced - *up * (landing.distance - MANTLE_EPSILON).max(0.0);
+            climb.mantle_path = vec![raised, advanced, target];
+            *mode = KCCMovementMode::Climb;
+            continue;
+        }
+
+        if climb.stamina <= 0.0 {
+            *mode = KCCMovementMode::Walk;
+        } else if *mode == KCCMovementMode::Walk {
+            *mode = KCCMovementMode::Climb;
+        }
+    }
+}
+
+/// Moves the character along its mantle path, leaving collisions aside since the path was
+/// checked to be free when it was found.
+fn mantle(
+    climb: &mut KCCClimb,
+    controller: &mut KinematicCharacterController,
+    transform: &mut Transform,
+    dt: f32,
+) {
+    controller.velocity = Vec3::ZERO;
+    let mut travel = climb.mantle_speed * dt;
+    while let Some(&point) = climb.mantle_path.first() {
+        let to_point = point - transform.translation;
+        let distance = to_point.length();
+        if distance > travel {
+            transform.translation += to_point / distance * travel;
+            return;
+        }
+        transform.translation = point;
+        travel -= distance;
+        climb.mantle_path.remove(0);
+    }
+}
@@ -0,0 +1,251 @@
+//! # Collision Module
+//!
+//! Shape casts used by the kinematic character controller, with a choice of backend for the
+//! terrain:
+//!
+//! - [`KCCCollisionBackend::Colliders`] casts against the trimesh colliders of the chunks.
+//! - [`KCCCollisionBackend::Voxels`] sweeps the character directly against the voxel grid, so
+//!   edits are collided with as soon as they are made, without waiting for the new trimesh.
+//!
+//! Every other body is always collided with through its physics collider.
+
+use avian3d::prelude::*;
+use bevy::{ecs::system::SystemParam, prelude::*};
+
+use crate::{
+    dig::terrain::{ChunkMesh, VOXEL_SCALE},
+    voxel::chunks_manager::{ChunksManager, ChunksReader},
+};
+
+/// How many bisection steps refine the distance of a voxel hit.
+const VOXEL_REFINE_STEPS: u32 = 8;
+
+/// Which representation of the terrain a kinematic character controller collides with.
+#[derive(Component, Reflect, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[reflect(Component)]
+pub enum KCCCollisionBackend {
+    /// Cast against the trimesh colliders of the terrain chunks.
+    #[default]
+    Colliders,
+    /// Sweep against the voxel data of the terrain chunks.
+    Voxels,
+}
+
+/// A hit returned by [`KCCCollision::cast_shape`].
+#[derive(Debug, Clone, Copy)]
+pub struct KCCHit {
+    /// How far the shape traveled along the cast direction before hitting something.
+    pub distance: f32,
+    /// Surface normal of what was hit, pointing towards the cast shape.
+    pub normal: Vec3,
+    /// Collider that was hit, `None` for the voxels of the terrain.
+    pub entity: Option<Entity>,
+}
+
+/// System parameter used by the controller systems to cast their shapes against the world.
+#[derive(SystemParam)]
+pub struct KCCCollision<'w, 's> {
+    spatial_query: SpatialQuery<'w, 's>,
+    chunks: ChunksReader<'w, 's>,
+    terrain_q: Query<'w, 's, Entity, With<ChunkMesh>>,
+}
+
+impl KCCCollision<'_, '_> {
+    /// Casts the collider of `entity` from `origin` along `direction`, returning the closest hit.
+    pub fn cast_shape(
+        &self,
+        backend: KCCCollisionBackend,
+        entity: Entity,
+        collider: &Collider,
+        origin: Vec3,
+        rotation: Quat,
+        direction: Dir3,
+        config: &ShapeCastConfig,
+    ) -> Option<KCCHit> {
+        let physics_hit = |filter: &SpatialQueryFilter| {
+            self.spatial_query
+                .cast_shape(collider, origin, rotation, direction, config, filter)
+                .map(|hit| KCCHit {
+                    distance: hit.distance,
+                    normal: hit.normal1,
+                    entity: Some(hit.entity),
+                })
+        };
+        match backend {
+            KCCCollisionBackend::Colliders => {
+                physics_hit(&SpatialQueryFilter::default().with_excluded_entities([entity]))
+            }
+            KCCCollisionBackend::Voxels => {
+                let filter = SpatialQueryFilter::default()
+                    .with_excluded_entities(self.terrain_q.iter().chain([entity]));
+                let voxel_hit = self.cast_voxels(collider, origin, rotation, direction, config);
+                match (physics_hit(&filter), voxel_hit) {
+                    (Some(a), Some(b)) => Some(if a.distance <= b.distance { a } else { b }),
+                    (a, b) => a.or(b),
+                }
+            }
+        }
+    }
+
+    /// Colliders overlapping the collider of `entity` placed at `origin`, the terrain left out.
+    pub fn intersections(
+        &self,
+        entity: Entity,
+        collider: &Collider,
+        origin: Vec3,
+        rotation: Quat,
+    ) -> Vec<Entity> {
+        let filter = SpatialQueryFilter::default()
+            .with_excluded_entities(self.terrain_q.iter().chain([entity]));
+        self.spatial_query
+            .shape_intersections(collider, origin, rotation, &filter)
+    }
+
+    /// Sweeps the collider through the voxel grid, solid voxels being treated as cubes centered
+    /// on their position, which is where the marching cubes surface lies.
+    /// A collider already overlapping solid voxels at `origin` hits them at a distance of zero.
+    fn cast_voxels(
+        &self,
+        collider: &Collider,
+        origin: Vec3,
+        rotation: Quat,
+        direction: Dir3,
+        config: &ShapeCastConfig,
+    ) -> Option<KCCHit> {
+        let capsule = VoxelCapsule::new(collider, origin, rotation, &self.chunks);
+        let max_distance = ChunksManager::world_length_to_voxel_length(config.max_distance);
+        let travel = *direction * max_distance;
+
+        // Every voxel the capsule could touch during the sweep
+        let margin = Vec3::splat(capsule.radius + 0.5);
+        let low = capsule.a.min(capsule.b);
+        let high = capsule.a.max(capsule.b);
+        let min = (low.min(low + travel) - margin).floor().as_ivec3();
+        let max = (high.max(high + travel) + margin).ceil().as_ivec3();
+        let mut solids = Vec::new();
+        for z in min.z..=max.z {
+            for y in min.y..=max.y {
+                for x in min.x..=max.x {
+                    let voxel = IVec3::new(x, y, z);
+                    if self.chunks.is_solid(voxel) {
+                        solids.push(voxel.as_vec3());
+                    }
+                }
+            }
+        }
+        if solids.is_empty() {
+            return None;
+        }
+
+        let push_out = |distance: f32| capsule.push_out(*direction * distance, &solids, direction);
+        if let Some(normal) = push_out(0.) {
+            return Some(KCCHit {
+                distance: 0.,
+                normal,
+                entity: None,
+            });
+        }
+
+        // March in steps small enough not to skip through a voxel, then refine the first overlap
+        let step = (capsule.radius * 0.5).min(0.5);
+        let mut free = 0.;
+        while free < max_distance {
+            let next = (free + step).min(max_distance);
+            if push_out(next).is_none() {
+                free = next;
+                continue;
+            }
+            let mut blocked = next;
+            for _ in 0..VOXEL_REFINE_STEPS {
+                let middle = (free + blocked) * 0.5;
+                if push_out(middle).is_some() {
+                    blocked = middle;
+                } else {
+                    free = middle;
+                }
+            }
+            return push_out(blocked).map(|normal| KCCHit {
+                distance: free * VOXEL_SCALE,
+                normal,
+                entity: None,
+            });
+        }
+        None
+    }
+}
+
+/// A capsule in the voxel space of the world.
+struct VoxelCapsule {
+    a: Vec3,
+    b: Vec3,
+    radius: f32,
+}
+
+impl VoxelCapsule {
+    /// Builds the capsule from a capsule collider, other shapes are approximated by the upright
+    /// capsule fitting in their bounding box.
+    fn new(
+        collider: &Collider,
+        origin: Vec3,
+        rotation: Quat,
+        chunks: &ChunksReader,
+    ) -> VoxelCapsule {
+        let (a, b, radius) = match collider.shape_scaled().as_capsule() {
+            Some(capsule) => {
+                let a = capsule.segment.a;
+                let b = capsule.segment.b;
+                (
+                    Vec3::new(a.x, a.y, a.z),
+                    Vec3::new(b.x, b.y, b.z),
+                    capsule.radius,
+                )
+            }
+            None => {
+                let aabb = collider.aabb(Vec3::ZERO, Quat::IDENTITY);
+                let center = (aabb.min + aabb.max) * 0.5;
+                let half_extents = (aabb.max - aabb.min) * 0.5;
+                let radius = half_extents.x.min(half_extents.z);
+                let half_height = (half_extents.y - radius).max(0.);
+                (
+                    center - Vec3::Y * half_height,
+                    center + Vec3::Y * half_height,
+                    radius,
+                )
+            }
+        };
+        VoxelCapsule {
+            a: chunks.world_pos_to_voxel_pos(origin + rotation * a),
+            b: chunks.world_pos_to_voxel_pos(origin + rotation * b),
+            radius: ChunksManager::world_length_to_voxel_length(radius),
+        }
+    }
+
+    /// Direction pushing the capsule, moved by `offset`, out of the solid voxels it overlaps.
+    /// The capsule is approximated by spheres along its segment.
+    fn push_out(&self, offset: Vec3, solids: &[Vec3], direction: Dir3) -> Option<Vec3> {
+        let spheres = (self.a.distance(self.b) / (self.radius * 0.5)).ceil() as usize + 1;
+        let radius_squared = self.radius * self.radius;
+        let mut push = Vec3::ZERO;
+        let mut overlaps = false;
+        for i in 0..spheres {
+            let t = if spheres > 1 {
+                i as f32 / (spheres - 1) as f32
+            } else {
+                0.
+            };
+            let center = self.a.lerp(self.b, t) + offset;
+            for solid in solids {
+                let closest = center.clamp(*solid - 0.5, *solid + 0.5);
+                let away = center - closest;
+                let distance_squared = away.length_squared();
+                if distance_squared >= radius_squared {
+                    continue;
+                }
+                overlaps = true;
+                let distance = distance_squared.sqrt();
+                push += away.normalize_or(-*direction) * (self.radius - distance);
+            }
+        }
+        overlaps.then(|| push.normalize_or(-*direction))
+    }
+}
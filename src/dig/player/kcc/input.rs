@@ -1,92 +1,187 @@
-use avian3d::{
-    math::Scalar,
-    prelude::{RigidBody, Rotation, ShapeHits},
-};
 use bevy::prelude::*;
 
-use crate::dig::player::kcc::movement;
+use crate::dig::{
+    input::{Action, ActionState},
+    player::kcc::movement,
+};
 
-use super::{KCCGravity, KinematicCharacterController};
+use super::{
+    KCCBuoyancy, KCCClimb, KCCCrouch, KCCGravity, KCCGrounded, KCCMovementMode,
+    KCCMovementSettings, KinematicCharacterController,
+};
+
+/// Speed of the characters flying up and down.
+const FLY_VERTICAL_SPEED: f32 = 8.0;
+/// How far forward the movement input has to be to hold on to walls.
+const CLIMB_INPUT_THRESHOLD: f32 = 0.5;
 
 pub fn kcc_input_plugin(app: &mut App) {
-    app.add_systems(
-        FixedUpdate,
-        (velocity_dampening, update_grounded, movement_input).chain(),
-    );
+    app.add_systems(FixedUpdate, movement_input)
+        .add_systems(Update, toggle_movement_mode);
 }
 
-// Marker component for whether or not we're currently grounded.
-#[derive(Component, Reflect, Debug, Default)]
-#[reflect(Component)]
-#[component(storage = "SparseSet")]
-pub struct Grounded;
+/// Switches the players between walking, flying and noclip.
+fn toggle_movement_mode(
+    mut player_query: Query<
+        (&ActionState, &mut KCCMovementMode),
+        With<KinematicCharacterController>,
+    >,
+) {
+    let toggle = |mode: KCCMovementMode, toggled: KCCMovementMode| {
+        if mode == toggled {
+            KCCMovementMode::Walk
+        } else {
+            toggled
+        }
+    };
+    for (actions, mut mode) in player_query.iter_mut() {
+        if actions.just_pressed(Action::ToggleFly) {
+            *mode = toggle(*mode, KCCMovementMode::Fly);
+        }
+        if actions.just_pressed(Action::ToggleNoclip) {
+            *mode = toggle(*mode, KCCMovementMode::Noclip);
+        }
+    }
+}
 
 /// System that handles player movement and camera rotation based on input
 ///
-/// This system processes player actions and updates the character's movement and camera
-/// orientation. It handles horizontal movement, jumping, and camera rotation using mouse input.
+/// This system processes the actions of every player and updates their character's movement.
+/// It handles horizontal movement, jumping, crouching and flying.
 pub fn movement_input(
-    keyboard: Res<ButtonInput<KeyCode>>,
     mut player_query: Query<(
+        &ActionState,
         &mut KinematicCharacterController,
-        Has<Grounded>,
+        Option<&KCCGrounded>,
         &mut Transform,
         Option<&mut KCCGravity>,
+        Option<&KCCBuoyancy>,
+        Option<&mut KCCCrouch>,
+        Option<&mut KCCClimb>,
+        Option<&KCCMovementMode>,
+        Option<&KCCMovementSettings>,
     )>,
     time: Res<Time>,
 ) {
-    // Early return if we can't get the player or camera
-    let Ok((mut kcc, grounded, mut player_transform, mut gravity)) = player_query.get_single_mut()
-    else {
-        return;
-    };
+    let default_settings = KCCMovementSettings::default();
 
-    update_player_movement(
-        &keyboard,
-        &mut kcc,
+    for (
+        actions,
+        mut kcc,
         grounded,
-        &player_transform,
-        gravity.as_deref_mut(),
-    );
+        player_transform,
+        mut gravity,
+        buoyancy,
+        mut crouch,
+        mut climb,
+        mode,
+        settings,
+    ) in player_query.iter_mut()
+    {
+        let mode = mode.copied().unwrap_or_default();
+        if let Some(crouch) = crouch.as_mut() {
+            // The crouch key flies down instead
+            crouch.wants_crouch = actions.pressed(Action::Crouch) && !mode.is_flying();
+        }
+        if let Some(climb) = climb.as_mut() {
+            climb.wants_climb = actions.movement.y > CLIMB_INPUT_THRESHOLD;
+        }
+
+        update_player_movement(
+            actions,
+            &mut kcc,
+            grounded.is_some_and(|grounded| grounded.grounded),
+            &player_transform,
+            gravity.as_deref_mut(),
+            buoyancy,
+            climb.as_deref(),
+            mode,
+            settings.unwrap_or(&default_settings),
+            crouch.map_or(1.0, |crouch| crouch.speed_multiplier()),
+            time.delta_secs(),
+        );
+    }
 }
 
 /// Updates the player's movement based on input
 fn update_player_movement(
-    keyboard: &Res<ButtonInput<KeyCode>>,
+    actions: &ActionState,
     kcc: &mut KinematicCharacterController,
     grounded: bool,
     player_transform: &Transform,
     gravity: Option<&mut KCCGravity>,
+    buoyancy: Option<&KCCBuoyancy>,
+    climb: Option<&KCCClimb>,
+    mode: KCCMovementMode,
+    settings: &KCCMovementSettings,
+    speed_multiplier: f32,
+    delta: f32,
 ) {
-    let mut movement = Vec2::ZERO;
-    if keyboard.pressed(KeyCode::KeyA) {
-        movement.x = -1.;
-    }
-    if keyboard.pressed(KeyCode::KeyD) {
-        movement.x = 1.;
-    }
-    if keyboard.pressed(KeyCode::KeyW) {
-        movement.y = 1.;
-    }
-    if keyboard.pressed(KeyCode::KeyS) {
-        movement.y = -1.;
+    let movement = actions.movement;
+
+    if let (KCCMovementMode::Climb, Some(climb)) = (mode, climb) {
+        // Forward climbs up the wall and sideways moves along it
+        let up = kcc
+            .up
+            .reject_from_normalized(climb.wall_normal)
+            .normalize_or_zero();
+        let right = kcc.up.cross(climb.wall_normal).normalize_or_zero();
+        kcc.velocity =
+            (up * movement.y + right * movement.x) * climb.climb_speed * speed_multiplier;
+        return;
     }
 
-    let direction = player_transform
+    let sprint = if actions.pressed(Action::Sprint) {
+        settings.sprint_multiplier
+    } else {
+        1.0
+    };
+    // Already at most 1 long, so sticks can walk slower than full speed
+    let wanted = player_transform
         .rotation
         .mul_vec3(Vec3::new(movement.x, 0.0, -movement.y))
-        .normalize_or_zero()
-        * 10.0;
+        * settings.walk_speed
+        * speed_multiplier
+        * sprint;
 
-    if movement != Vec2::ZERO {
-        kcc.velocity.x = direction.x;
-        kcc.velocity.z = direction.z;
+    // Speed up and slow down by the grip of the ground, only partly in the air
+    let control = match mode {
+        KCCMovementMode::Walk if grounded => settings.friction,
+        KCCMovementMode::Walk => settings.air_control,
+        _ => 1.0,
+    };
+    let rate = if movement != Vec2::ZERO {
+        settings.ground_acceleration
+    } else {
+        settings.ground_deceleration
+    };
+    let horizontal =
+        Vec3::new(kcc.velocity.x, 0.0, kcc.velocity.z).move_towards(wanted, rate * control * delta);
+    kcc.velocity.x = horizontal.x;
+    kcc.velocity.z = horizontal.z;
+
+    if mode.is_flying() {
+        let vertical =
+            actions.pressed(Action::Jump) as i32 - actions.pressed(Action::Crouch) as i32;
+        kcc.velocity.y = vertical as f32 * FLY_VERTICAL_SPEED;
+        return;
     }
+    kcc.velocity.y = 0.0;
 
-    // Handle jumping through gravity system
-    if keyboard.pressed(KeyCode::Space) && grounded {
+    // Handle jumping and swimming through gravity system
+    if actions.pressed(Action::Jump) {
         if let Some(gravity) = gravity {
-            gravity.current_velocity = Vec3::Y * 5.0;
+            match (mode, buoyancy) {
+                (KCCMovementMode::Swim, Some(buoyancy)) => {
+                    gravity.current_velocity = Vec3::Y * buoyancy.swim_speed;
+                }
+                _ if grounded => {
+                    // Launch speed that gravity slows to a stop at the jump height
+                    let speed = (2.0 * gravity.acceleration_factor * settings.jump_height).sqrt();
+                    gravity.current_velocity = Vec3::Y * speed;
+                }
+                _ => {}
+            }
         }
     }
 }
@@ -108,25 +203,3 @@ fn update_camera_rotation(
     player_transform.rotation = Quat::from_rotation_y(yaw);
 }
  */
-pub fn velocity_dampening(mut query: Query<&mut KinematicCharacterController>, _time: Res<Time>) {
-    for mut kcc in query.iter_mut() {
-        kcc.velocity.x *= 0.9;
-        kcc.velocity.z *= 0.9;
-    }
-}
-
-fn update_grounded(
-    mut commands: Commands,
-    mut query: Query<(Entity, &ShapeHits, &Rotation), (With<KCCGravity>, With<RigidBody>)>,
-) {
-    let _ = (45.0 as Scalar).to_radians();
-    for (entity, hits, _) in &mut query {
-        let is_grounded = hits.iter().any(|hit| true && hit.entity != entity);
-
-        if is_grounded {
-            commands.entity(entity).insert(Grounded);
-        } else {
-            commands.entity(entity).remove::<Grounded>();
-        }
-    }
-}
@@ -0,0 +1,72 @@
+//! # Interpolation Module
+//!
+//! The controller moves its characters in [`FixedUpdate`], which runs more or less often than the
+//! frames are rendered. Characters with a [`KCCInterpolation`] are shown between the positions of
+//! their last two ticks instead, so they, and the cameras attached to them, move smoothly.
+//!
+//! The [`Transform`] holds the simulated translation during the fixed ticks and the interpolated
+//! one the rest of the frame. A translation changed outside of the ticks is taken as a teleport.
+
+use bevy::prelude::*;
+
+pub fn kcc_interpolation_plugin(app: &mut App) {
+    app.register_type::<KCCInterpolation>()
+        .add_systems(FixedPreUpdate, restore_simulated_translation)
+        .add_systems(FixedLast, record_simulated_translation)
+        .add_systems(
+            PostUpdate,
+            interpolate_translation.before(TransformSystem::TransformPropagate),
+        );
+}
+
+/// Component that smooths the rendered movement of the character between fixed ticks.
+#[derive(Component, Reflect, Debug, Default)]
+#[reflect(Component)]
+pub struct KCCInterpolation {
+    /// Translation at the end of the tick before the last one.
+    pub previous_translation: Vec3,
+    /// Translation at the end of the last tick.
+    pub translation: Vec3,
+    /// Translation the transform was last left with, to notice it being moved from elsewhere.
+    shown_translation: Vec3,
+}
+
+/// Puts the characters back where the simulation left them before a tick.
+fn restore_simulated_translation(mut query: Query<(&mut Transform, &mut KCCInterpolation)>) {
+    for (mut transform, mut interpolation) in query.iter_mut() {
+        if transform.translation != interpolation.shown_translation {
+            // Teleported, don't interpolate from where the character was
+            interpolation.previous_translation = transform.translation;
+            interpolation.translation = transform.translation;
+            continue;
+        }
+        transform.translation = interpolation.translation;
+    }
+}
+
+fn record_simulated_translation(mut query: Query<(&Transform, &mut KCCInterpolation)>) {
+    for (transform, mut interpolation) in query.iter_mut() {
+        interpolation.previous_translation = interpolation.translation;
+        interpolation.translation = transform.translation;
+        interpolation.shown_translation = transform.translation;
+    }
+}
+
+/// Shows the characters part of the way through their last tick, by how far the frame is into
+/// the next one.
+fn interpolate_translation(
+    time: Res<Time<Fixed>>,
+    mut query: Query<(&mut Transform, &mut KCCInterpolation)>,
+) {
+    let alpha = time.overstep_fraction();
+    for (mut transform, mut interpolation) in query.iter_mut() {
+        if transform.translation != interpolation.shown_translation {
+            continue;
+        }
+        let translation = interpolation
+            .previous_translation
+            .lerp(interpolation.translation, alpha);
+        transform.translation = translation;
+        interpolation.shown_translation = translation;
+    }
+}
@@ -3,26 +3,55 @@
 //!
 //! Please note that all components within this module are prefixed with `KCC` to make it clear that
 //! they are part of the Kinematic Character Controller framework.
+//!
+//! The whole controller runs in [`FixedUpdate`], so it behaves the same at any frame rate, see the
+//! interpolation module for how it is shown in between.
 
 use avian3d::prelude::*;
 use bevy::prelude::*;
+use collision::KCCCollision;
 use input::kcc_input_plugin;
+use interpolation::kcc_interpolation_plugin;
+
+use crate::dig::terrain::Submerged;
+
+pub use climb::KCCClimb;
+pub use collision::KCCCollisionBackend;
+pub use interpolation::KCCInterpolation;
+pub use push::KCCPush;
 
+mod climb;
+mod collision;
 mod input;
+mod interpolation;
 mod movement;
+mod push;
 
 pub(super) fn plugin(app: &mut App) {
-    app.add_plugins(kcc_input_plugin).add_systems(
-        PostUpdate,
-        (
-            movement::gravity_system,
-            movement::collide_and_slide_system,
-            update_kinematic_character_controller,
-            update_kinematic_floor,
-            floor_snap,
-        )
-            .chain(),
-    );
+    app.add_plugins((kcc_input_plugin, kcc_interpolation_plugin))
+        .register_type::<KCCCollisionBackend>()
+        .register_type::<KCCBuoyancy>()
+        .register_type::<KCCCrouch>()
+        .register_type::<KCCClimb>()
+        .register_type::<KCCPush>()
+        .register_type::<KCCMovementMode>()
+        .register_type::<KCCMovementSettings>()
+        .add_systems(
+            FixedUpdate,
+            (
+                update_swimming,
+                climb::update_climbing,
+                update_crouch,
+                movement::gravity_system,
+                movement::collide_and_slide_system,
+                update_kinematic_character_controller,
+                update_kinematic_floor,
+                update_floor_velocity,
+                floor_snap,
+            )
+                .chain()
+                .after(input::movement_input),
+        );
 }
 
 /// A component that represents the core logic of a kinematic character controller.
@@ -39,6 +68,8 @@ pub struct KinematicCharacterController {
     pub up: Vec3,
     /// How many times the collider will "bounce" off of surfaces.
     pub bounces: u32,
+    /// Tallest ledge the character walks up onto, and deepest drop it stays grounded over.
+    pub max_step_height: f32,
     /// The collider that represents the shape of this character.
     #[reflect(ignore)]
     pub collider: Collider,
@@ -51,6 +82,7 @@ impl Default for KinematicCharacterController {
             velocity: Vec3::ZERO,
             up: Vec3::Y,
             bounces: 4,
+            max_step_height: 0.3,
             collider: Collider::capsule(0.4, 0.8),
         }
     }
@@ -86,6 +118,14 @@ pub struct KCCFloorDetection {
     pub floor_distance: f32,
     /// How far from the floor this character can be before it is considered not grounded.
     pub max_floor_distance: f32,
+    /// The collider we are currently standing on, `None` if we are not grounded or standing on
+    /// the voxels of the terrain.
+    pub floor_entity: Option<Entity>,
+    /// Velocity of the floor under the character, carried along with it.
+    /// Kept after leaving the floor, so jumping off a moving platform preserves its momentum.
+    pub floor_velocity: Vec3,
+    /// Angular velocity of the floor, turning the character along with it while grounded.
+    pub floor_angular_velocity: Vec3,
 }
 
 impl Default for KCCFloorDetection {
@@ -97,6 +137,9 @@ impl Default for KCCFloorDetection {
             floor_collider: Collider::capsule(0.4, 0.8),
             floor_distance: 0.0,
             max_floor_distance: 0.05,
+            floor_entity: None,
+            floor_velocity: Vec3::ZERO,
+            floor_angular_velocity: Vec3::ZERO,
         }
     }
 }
@@ -133,6 +176,151 @@ impl Default for KCCGravity {
     }
 }
 
+/// Component that tunes how the character moves under its own power.
+/// Characters without it move with the default settings.
+#[derive(Component, Reflect, Debug, Clone)]
+#[reflect(Component)]
+pub struct KCCMovementSettings {
+    /// Top speed when walking.
+    pub walk_speed: f32,
+    /// How quickly the character speeds up towards the wanted velocity on the ground, per second.
+    pub ground_acceleration: f32,
+    /// How quickly the character comes to a stop on the ground without input, per second.
+    pub ground_deceleration: f32,
+    /// Fraction of the ground acceleration and deceleration left in the air.
+    pub air_control: f32,
+    /// Multiplier applied to the speed while sprinting.
+    pub sprint_multiplier: f32,
+    /// How high a jump lifts the character, given its gravity.
+    pub jump_height: f32,
+    /// Grip of the ground, scaling the ground acceleration and deceleration. Lower is slipperier.
+    pub friction: f32,
+}
+
+impl Default for KCCMovementSettings {
+    fn default() -> Self {
+        Self {
+            walk_speed: 10.0,
+            ground_acceleration: 100.0,
+            ground_deceleration: 60.0,
+            air_control: 0.3,
+            sprint_multiplier: 1.5,
+            jump_height: 0.65,
+            friction: 1.0,
+        }
+    }
+}
+
+/// How a kinematic character controller moves, switchable at runtime.
+#[derive(Component, Reflect, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[reflect(Component)]
+pub enum KCCMovementMode {
+    /// Walks on the ground, pulled down by gravity.
+    #[default]
+    Walk,
+    /// Floats in water, see [`KCCBuoyancy`].
+    /// Walking characters with buoyancy switch to swimming and back on their own.
+    Swim,
+    /// Moves in every direction without gravity, still colliding with the world.
+    Fly,
+    /// Moves in every direction without gravity, going through everything.
+    Noclip,
+    /// Holds on to a wall too steep to walk on, see [`KCCClimb`].
+    Climb,
+}
+
+impl KCCMovementMode {
+    /// Whether gravity is ignored and the character moves up and down on its own.
+    pub fn is_flying(self) -> bool {
+        matches!(self, KCCMovementMode::Fly | KCCMovementMode::Noclip)
+    }
+
+    /// Whether gravity pulls the character down.
+    pub fn has_gravity(self) -> bool {
+        !self.is_flying() && self != KCCMovementMode::Climb
+    }
+}
+
+/// Component that lets the character crouch, shrinking its colliders to fit through low tunnels.
+/// The character's colliders are replaced by capsules built from this component, and it only
+/// stands back up once a shape cast finds enough headroom.
+#[derive(Component, Reflect, Debug)]
+#[reflect(Component)]
+pub struct KCCCrouch {
+    /// Whether the character should crouch, set by the input.
+    pub wants_crouch: bool,
+    /// Whether the character is currently crouching.
+    pub crouching: bool,
+    /// Radius of the character's capsule.
+    pub radius: f32,
+    /// Length of the capsule's segment when standing.
+    pub standing_length: f32,
+    /// Length of the capsule's segment when crouching.
+    pub crouching_length: f32,
+    /// Multiplier applied to the movement speed while crouching.
+    pub speed_multiplier: f32,
+}
+
+impl Default for KCCCrouch {
+    fn default() -> Self {
+        Self {
+            wants_crouch: false,
+            crouching: false,
+            radius: 0.4,
+            standing_length: 0.8,
+            crouching_length: 0.2,
+            speed_multiplier: 0.5,
+        }
+    }
+}
+
+impl KCCCrouch {
+    /// The collider of the character in its current stance.
+    pub fn collider(&self) -> Collider {
+        let length = if self.crouching {
+            self.crouching_length
+        } else {
+            self.standing_length
+        };
+        Collider::capsule(self.radius, length)
+    }
+
+    /// Multiplier applied to the movement speed in the current stance.
+    pub fn speed_multiplier(&self) -> f32 {
+        if self.crouching {
+            self.speed_multiplier
+        } else {
+            1.0
+        }
+    }
+}
+
+/// Component that lets the character float and swim in water.
+/// This component requires the [`Submerged`] component to be present on the same entity.
+#[derive(Component, Reflect, Debug)]
+#[reflect(Component)]
+pub struct KCCBuoyancy {
+    /// Fraction of gravity cancelled when fully submerged, above 1 the character floats up.
+    pub buoyancy: f32,
+    /// How quickly the water slows the character's fall down, per second.
+    pub drag: f32,
+    /// Upward speed when swimming.
+    pub swim_speed: f32,
+    /// Fraction of the character that has to be under water to swim instead of walking.
+    pub swim_depth: f32,
+}
+
+impl Default for KCCBuoyancy {
+    fn default() -> Self {
+        Self {
+            buoyancy: 1.1,
+            drag: 3.0,
+            swim_speed: 3.0,
+            swim_depth: 0.3,
+        }
+    }
+}
+
 /// Component that controls how the character handles slopes
 #[derive(Component, Reflect, Debug)]
 #[reflect(Component)]
@@ -152,6 +340,70 @@ impl Default for KCCSlope {
     }
 }
 
+/// Switches the characters deep enough in water to swimming, and back to walking once out.
+pub fn update_swimming(mut query: Query<(&mut KCCMovementMode, &KCCBuoyancy, &Submerged)>) {
+    for (mut mode, buoyancy, submerged) in query.iter_mut() {
+        let swimming = submerged.fraction > buoyancy.swim_depth;
+        match *mode {
+            KCCMovementMode::Walk if swimming => *mode = KCCMovementMode::Swim,
+            KCCMovementMode::Swim if !swimming => *mode = KCCMovementMode::Walk,
+            _ => {}
+        }
+    }
+}
+
+/// Crouches and stands the characters up as requested, keeping their feet in place.
+/// Standing up is delayed until the taller collider fits.
+pub fn update_crouch(
+    mut query: Query<(
+        Entity,
+        &mut KCCCrouch,
+        &mut KinematicCharacterController,
+        &mut Transform,
+        Option<&mut KCCFloorDetection>,
+        Option<&KCCCollisionBackend>,
+    )>,
+    collision: KCCCollision,
+) {
+    for (entity, mut crouch, mut controller, mut transform, floor_detection, backend) in
+        query.iter_mut()
+    {
+        if crouch.wants_crouch == crouch.crouching {
+            continue;
+        }
+        let Ok(up) = Dir3::new(controller.up) else {
+            continue;
+        };
+        let growth = crouch.standing_length - crouch.crouching_length;
+        if crouch.wants_crouch {
+            transform.translation -= *up * growth * 0.5;
+        } else {
+            let blocked = collision
+                .cast_shape(
+                    backend.copied().unwrap_or_default(),
+                    entity,
+                    &controller.collider,
+                    transform.translation,
+                    transform.rotation,
+                    up,
+                    &ShapeCastConfig::from_max_distance(growth),
+                )
+                .is_some();
+            if blocked {
+                continue;
+            }
+            transform.translation += *up * growth * 0.5;
+        }
+
+        crouch.crouching = crouch.wants_crouch;
+        let collider = crouch.collider();
+        if let Some(mut floor_detection) = floor_detection {
+            floor_detection.floor_collider = collider.clone();
+        }
+        controller.collider = collider;
+    }
+}
+
 /// Function that updates the kinematic character controller's internal state. Currently, this only
 /// updates the previous velocity.
 pub fn update_kinematic_character_controller(
@@ -168,36 +420,76 @@ pub fn update_kinematic_floor(
         &mut KCCFloorDetection,
         &Transform,
         Option<&mut KCCGrounded>,
+        Option<&KCCCollisionBackend>,
         Entity,
     )>,
-    spatial_query: SpatialQuery,
+    collision: KCCCollision,
 ) {
-    for (mut floor_detection, transform, mut grounded, entity) in query.iter_mut() {
+    for (mut floor_detection, transform, mut grounded, backend, entity) in query.iter_mut() {
         floor_detection.prev_floor_normal = floor_detection.floor_normal;
         if let Some(grounded) = grounded.as_mut() {
             grounded.prev_grounded = grounded.grounded;
         }
 
-        let Some(cast) = spatial_query.cast_shape(
+        let Some(cast) = collision.cast_shape(
+            backend.copied().unwrap_or_default(),
+            entity,
             &floor_detection.floor_collider,
             transform.translation,
             Quat::IDENTITY,
             Dir3::new_unchecked(floor_detection.ground_direction.normalize()),
             &ShapeCastConfig::from_max_distance(floor_detection.max_floor_distance),
-            &SpatialQueryFilter::default().with_excluded_entities([entity]),
         ) else {
-            // Nothing was hit, move on.
+            // Nothing was hit, we are in the air.
+            floor_detection.floor_normal = Vec3::ZERO;
+            floor_detection.floor_entity = None;
+            if let Some(grounded) = grounded.as_mut() {
+                grounded.grounded = false;
+            }
             continue;
         };
 
-        floor_detection.floor_normal = cast.normal1;
+        floor_detection.floor_normal = cast.normal;
         floor_detection.floor_distance = cast.distance;
+        floor_detection.floor_entity = cast.entity;
         if let Some(grounded) = grounded.as_mut() {
             grounded.grounded = true;
         }
     }
 }
 
+/// Measures the velocity of the floor at the character's position, so the character moves along
+/// with moving platforms. Colliders attached to a body move with the body.
+pub fn update_floor_velocity(
+    mut query: Query<(&mut KCCFloorDetection, &Transform)>,
+    bodies_q: Query<(&LinearVelocity, &AngularVelocity, &GlobalTransform)>,
+    collider_parents_q: Query<&ColliderParent>,
+) {
+    for (mut floor_detection, transform) in query.iter_mut() {
+        let Some(floor) = floor_detection.floor_entity else {
+            if floor_detection.floor_normal != Vec3::ZERO {
+                // Standing on the terrain
+                floor_detection.floor_velocity = Vec3::ZERO;
+            }
+            // Keep the momentum of the last floor while in the air, but stop turning
+            floor_detection.floor_angular_velocity = Vec3::ZERO;
+            continue;
+        };
+        let body = collider_parents_q
+            .get(floor)
+            .map_or(floor, |parent| parent.get());
+        let (linear, angular) = match bodies_q.get(body) {
+            Ok((linear, angular, body_transform)) => {
+                let offset = transform.translation - body_transform.translation();
+                (linear.0 + angular.0.cross(offset), angular.0)
+            }
+            Err(_) => (Vec3::ZERO, Vec3::ZERO),
+        };
+        floor_detection.floor_velocity = linear;
+        floor_detection.floor_angular_velocity = angular;
+    }
+}
+
 pub fn floor_snap(
     mut query: Query<(
         &mut Transform,
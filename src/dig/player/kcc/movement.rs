@@ -7,6 +7,8 @@
 //!
 //! - Multi-pass collision detection and response
 //! - Slope-aware movement
+//! - Stepping up ledges and down small drops
+//! - Pushing dynamic bodies around, and being pushed by them
 //! - Configurable gravity with terminal velocity
 //! - Efficient depenetration system
 //!
@@ -18,13 +20,22 @@
 use avian3d::{math::AdjustPrecision, prelude::*};
 use bevy::prelude::*;
 
-use super::{KCCFloorDetection, KCCGravity, KCCSlope, KinematicCharacterController};
+use crate::dig::terrain::Submerged;
+
+use super::{
+    collision::{KCCCollision, KCCCollisionBackend},
+    push::{KCCBodies, KCCPush},
+    KCCBuoyancy, KCCFloorDetection, KCCGravity, KCCGrounded, KCCMovementMode, KCCSlope,
+    KinematicCharacterController,
+};
 
 // Movement configuration constants
 const MAX_BUMPS: u32 = 4;
 const MIN_MOVEMENT: f32 = 0.0001;
 const COLLISION_EPSILON: f32 = 0.01;
 const DEPENETRATION_EPSILON: f32 = 0.01;
+/// Steepest surface a step can be settled on when the character has no [`KCCSlope`].
+const DEFAULT_MAX_SLOPE_ANGLE: f32 = std::f32::consts::FRAC_PI_4;
 
 /// Result of a movement calculation iteration
 #[derive(Debug)]
@@ -32,13 +43,14 @@ struct MovementResult {
     movement: Vec3,
     remaining_velocity: Vec3,
     hit_normal: Option<Vec3>,
+    /// Colliders hit along the way, with the normal they were hit with.
+    hit_colliders: Vec<(Entity, Vec3)>,
 }
 
 /// Main system for handling character movement and collision response
 ///
 /// Processes both horizontal movement and gravity in separate passes to ensure
 /// proper collision response in all scenarios.
-#[allow(clippy::too_many_arguments)]
 pub fn collide_and_slide_system(
     mut query: Query<
         (
@@ -46,44 +58,138 @@ pub fn collide_and_slide_system(
             Entity,
             &mut KinematicCharacterController,
             Option<&KCCSlope>,
-            Option<&KCCFloorDetection>,
+            Option<&mut KCCFloorDetection>,
             Option<&mut KCCGravity>,
+            Option<&KCCCollisionBackend>,
+            Option<&KCCGrounded>,
+            Option<&KCCMovementMode>,
+            Option<&KCCPush>,
         ),
         With<RigidBody>,
     >,
-    mut spatial_query: SpatialQuery,
+    collision: KCCCollision,
+    mut bodies: KCCBodies,
     time: Res<Time>,
 ) {
     let delta = time.delta_secs_f64().adjust_precision();
 
-    for (mut transform, entity, mut controller, slope, floor_detection, gravity) in &mut query {
-        let filter = SpatialQueryFilter::default().with_excluded_entities([entity]);
+    for (
+        mut transform,
+        entity,
+        mut controller,
+        slope,
+        mut floor_detection,
+        gravity,
+        backend,
+        grounded,
+        mode,
+        push,
+    ) in &mut query
+    {
+        let mode = mode.copied().unwrap_or_default();
+        if mode == KCCMovementMode::Noclip {
+            transform.translation += controller.velocity * delta;
+            continue;
+        }
+
+        let cast = Caster {
+            collision: &collision,
+            backend: backend.copied().unwrap_or_default(),
+            entity,
+        };
+        // Steps are only taken while walking, not while jumping or falling
+        let walking = mode == KCCMovementMode::Walk
+            && grounded.is_some_and(|grounded| grounded.grounded)
+            && gravity
+                .as_ref()
+                .is_none_or(|gravity| gravity.current_velocity.dot(controller.up) <= 0.0);
 
         // Process horizontal movement
+        let velocity = controller.velocity;
         let movement = process_movement(
-            &mut spatial_query,
-            &filter,
+            &cast,
             &controller,
             &mut transform,
             controller.velocity * delta,
             slope,
-            floor_detection,
+            floor_detection.as_deref(),
             false,
+            walking,
         );
 
-        controller.velocity = movement.remaining_velocity / delta;
+        // Keep the velocity we actually moved at when something was in the way, so speed is lost
+        // against walls. Moves too small to be made are left alone, so speed still builds up
+        // from rest over ticks
+        if movement.hit_normal.is_some() {
+            controller.velocity = movement.movement / delta;
+        }
+
+        // Push the dynamic bodies we walked into
+        if let Some(push) = push {
+            for &(collider, normal) in &movement.hit_colliders {
+                bodies.push(collider, -normal, velocity, push.mass);
+            }
+        }
+
+        if walking && movement.movement != Vec3::ZERO {
+            step_down(&cast, &controller, &mut transform, slope);
+        }
+
+        // Move along with the floor, or keep the momentum it gave us
+        if let Some(floor_detection) = floor_detection.as_mut() {
+            let movement = process_movement(
+                &cast,
+                &controller,
+                &mut transform,
+                floor_detection.floor_velocity * delta,
+                slope,
+                None,
+                false,
+                false,
+            );
+            if movement.hit_normal.is_some() {
+                floor_detection.floor_velocity = movement.remaining_velocity / delta;
+            }
+            let turn = floor_detection.floor_angular_velocity.dot(controller.up) * delta;
+            if turn != 0.0 {
+                if let Ok(up) = Dir3::new(controller.up) {
+                    transform.rotate_axis(up, turn);
+                }
+            }
+        }
+
+        // Get pushed away by the dynamic bodies moving into us
+        if let Some(push) = push.filter(|push| push.pushable) {
+            let colliders = collision.intersections(
+                entity,
+                &controller.collider,
+                transform.translation,
+                transform.rotation,
+            );
+            let pushed = bodies.pushed_velocity(colliders, transform.translation, push.mass);
+            process_movement(
+                &cast,
+                &controller,
+                &mut transform,
+                pushed * delta,
+                slope,
+                None,
+                false,
+                false,
+            );
+        }
 
         // Process gravity separately if enabled
         if let Some(mut gravity) = gravity {
             let movement = process_movement(
-                &mut spatial_query,
-                &filter,
+                &cast,
                 &controller,
                 &mut transform,
                 gravity.current_velocity * delta,
                 slope,
-                floor_detection,
+                floor_detection.as_deref(),
                 true,
+                false,
             );
 
             // Update gravity velocity based on collision results
@@ -93,34 +199,39 @@ pub fn collide_and_slide_system(
         }
 
         // Perform depenetration
-        depenetrate(
-            &mut spatial_query,
-            &filter,
-            &controller.collider,
-            &mut transform,
-        );
+        depenetrate(&cast, &controller.collider, &mut transform);
     }
 }
 
+/// The collision queries of a single character.
+struct Caster<'a, 'w, 's> {
+    collision: &'a KCCCollision<'w, 's>,
+    backend: KCCCollisionBackend,
+    entity: Entity,
+}
+
 /// Core movement processing function that handles collision detection and response
 ///
 /// Returns a `MovementResult` containing the actual movement performed and any
 /// remaining velocity that couldn't be applied due to collisions.
+/// When `can_step` is set, walls lower than the max step height are stepped onto instead of slid
+/// along.
 fn process_movement(
-    spatial_query: &mut spatial_query::SpatialQuery,
-    filter: &spatial_query::SpatialQueryFilter,
+    cast: &Caster,
     controller: &KinematicCharacterController,
     transform: &mut Transform,
     velocity: Vec3,
     slope: Option<&KCCSlope>,
     floor_detection: Option<&KCCFloorDetection>,
     is_gravity_pass: bool,
+    can_step: bool,
 ) -> MovementResult {
     if velocity.length_squared() < MIN_MOVEMENT {
         return MovementResult {
             movement: Vec3::ZERO,
             remaining_velocity: Vec3::ZERO,
             hit_normal: None,
+            hit_colliders: Vec::new(),
         };
     }
 
@@ -128,6 +239,7 @@ fn process_movement(
     let mut current_velocity = velocity;
     let mut collision_planes = Vec::with_capacity(MAX_BUMPS as usize);
     let mut last_hit_normal = None;
+    let mut hit_colliders = Vec::new();
 
     for _ in 0..MAX_BUMPS {
         if current_velocity.length_squared() < MIN_MOVEMENT {
@@ -139,7 +251,9 @@ fn process_movement(
             Err(_) => break,
         };
 
-        match spatial_query.cast_shape(
+        match cast.collision.cast_shape(
+            cast.backend,
+            cast.entity,
             &controller.collider,
             transform.translation,
             transform.rotation,
@@ -149,26 +263,36 @@ fn process_movement(
                 max_distance: length,
                 ..default()
             },
-            filter,
         ) {
             Some(hit) => {
                 let safe_distance = (hit.distance - COLLISION_EPSILON).max(0.0);
-                let safe_movement = current_velocity * safe_distance;
+                let safe_movement = velocity_dir * safe_distance;
 
                 transform.translation += safe_movement;
                 total_movement += safe_movement;
                 current_velocity -= safe_movement;
-                last_hit_normal = Some(hit.normal1);
+                last_hit_normal = Some(hit.normal);
+                if let Some(entity) = hit.entity {
+                    hit_colliders.push((entity, hit.normal));
+                }
 
-                if is_gravity_pass && should_stop_on_slope(slope, floor_detection, hit.normal1) {
+                if is_gravity_pass && should_stop_on_slope(slope, floor_detection, hit.normal) {
                     break;
                 }
 
-                current_velocity = calculate_sliding_velocity(
-                    &mut collision_planes,
-                    hit.normal1,
-                    current_velocity,
-                );
+                if can_step && !is_walkable(slope, controller.up, hit.normal) {
+                    let start = transform.translation;
+                    if let Some(stepped) =
+                        step_up(cast, controller, transform, current_velocity, slope)
+                    {
+                        total_movement += transform.translation - start;
+                        current_velocity -= stepped;
+                        continue;
+                    }
+                }
+
+                current_velocity =
+                    calculate_sliding_velocity(&mut collision_planes, hit.normal, current_velocity);
             }
             None => {
                 transform.translation += current_velocity;
@@ -183,6 +307,94 @@ fn process_movement(
         movement: total_movement,
         remaining_velocity: current_velocity,
         hit_normal: last_hit_normal,
+        hit_colliders,
+    }
+}
+
+/// Whether the character can stand on a surface with this normal.
+#[inline]
+pub(super) fn is_walkable(slope: Option<&KCCSlope>, up: Vec3, normal: Vec3) -> bool {
+    let max_angle = slope.map_or(DEFAULT_MAX_SLOPE_ANGLE, |slope| slope.max_slope_angle);
+    normal.angle_between(up) < max_angle
+}
+
+/// Tries to climb onto the ledge blocking `velocity`: moves up by at most the max step height,
+/// forward along the horizontal part of `velocity`, then back down onto walkable ground.
+/// Returns the forward movement that was made, leaving the character untouched on failure.
+fn step_up(
+    cast: &Caster,
+    controller: &KinematicCharacterController,
+    transform: &mut Transform,
+    velocity: Vec3,
+    slope: Option<&KCCSlope>,
+) -> Option<Vec3> {
+    let up = Dir3::new(controller.up).ok()?;
+    let (forward, length) = Dir3::new_and_length(velocity.reject_from(*up)).ok()?;
+    let cast_from = |origin: Vec3, direction: Dir3, distance: f32| {
+        cast.collision.cast_shape(
+            cast.backend,
+            cast.entity,
+            &controller.collider,
+            origin,
+            transform.rotation,
+            direction,
+            &ShapeCastConfig {
+                ignore_origin_penetration: false,
+                max_distance: distance,
+                ..default()
+            },
+        )
+    };
+
+    let rise = cast_from(transform.translation, up, controller.max_step_height)
+        .map_or(controller.max_step_height, |hit| {
+            (hit.distance - COLLISION_EPSILON).max(0.0)
+        });
+    if rise <= COLLISION_EPSILON {
+        return None;
+    }
+    let raised = transform.translation + *up * rise;
+
+    let advance = cast_from(raised, forward, length)
+        .map_or(length, |hit| (hit.distance - COLLISION_EPSILON).max(0.0));
+    if advance <= MIN_MOVEMENT {
+        return None;
+    }
+    let advanced = raised + *forward * advance;
+
+    // The step has to end on something we can stand on, otherwise we'd be climbing a wall
+    let landing = cast_from(advanced, -up, rise)?;
+    if !is_walkable(slope, *up, landing.normal) {
+        return None;
+    }
+    transform.translation = advanced - *up * (landing.distance - COLLISION_EPSILON).max(0.0);
+    Some(*forward * advance)
+}
+
+/// Keeps a walking character on the ground when it walks off a drop no deeper than the max step
+/// height, instead of letting it go airborne.
+fn step_down(
+    cast: &Caster,
+    controller: &KinematicCharacterController,
+    transform: &mut Transform,
+    slope: Option<&KCCSlope>,
+) {
+    let Ok(up) = Dir3::new(controller.up) else {
+        return;
+    };
+    let Some(hit) = cast.collision.cast_shape(
+        cast.backend,
+        cast.entity,
+        &controller.collider,
+        transform.translation,
+        transform.rotation,
+        -up,
+        &ShapeCastConfig::from_max_distance(controller.max_step_height),
+    ) else {
+        return;
+    };
+    if hit.distance > COLLISION_EPSILON && is_walkable(slope, *up, hit.normal) {
+        transform.translation -= *up * (hit.distance - COLLISION_EPSILON);
     }
 }
 
@@ -217,39 +429,52 @@ fn calculate_sliding_velocity(planes: &mut Vec<Vec3>, normal: Vec3, velocity: Ve
 /// Performs depenetration for a kinematic character controller.
 ///
 /// # Arguments
-/// * `spatial_query` - Spatial query system for collision detection
-/// * `filter` - Filter to exclude specific entities from collision checks
+/// * `cast` - Collision queries of the character
 /// * `collider` - Collider of the character
 /// * `transform` - Transform of the character to update
-fn depenetrate(
-    spatial_query: &mut spatial_query::SpatialQuery,
-    filter: &spatial_query::SpatialQueryFilter,
-    collider: &Collider,
-    transform: &mut Transform,
-) {
-    let hit = spatial_query.cast_shape(
+fn depenetrate(cast: &Caster, collider: &Collider, transform: &mut Transform) {
+    let hit = cast.collision.cast_shape(
+        cast.backend,
+        cast.entity,
         collider,
         transform.translation,
         transform.rotation,
         Dir3::NEG_Y,
         &ShapeCastConfig::from_max_distance(0.0),
-        filter,
     );
 
     if let Some(hit) = hit {
         let push_out_distance = hit.distance + DEPENETRATION_EPSILON;
-        transform.translation += hit.normal1 * push_out_distance;
+        transform.translation += hit.normal * push_out_distance;
     }
 }
 
 /// Optimized gravity system with terminal velocity handling
 pub fn gravity_system(
-    mut query: Query<(&KinematicCharacterController, &mut KCCGravity)>,
+    mut query: Query<(
+        &KinematicCharacterController,
+        &mut KCCGravity,
+        Option<(&KCCBuoyancy, &Submerged)>,
+        Option<&KCCMovementMode>,
+    )>,
     time: Res<Time>,
 ) {
     let dt = time.delta_secs();
 
-    for (_, mut gravity) in query.iter_mut() {
+    for (_, mut gravity, swimming, mode) in query.iter_mut() {
+        let mode = mode.copied().unwrap_or_default();
+        if !mode.has_gravity() {
+            gravity.current_velocity = Vec3::ZERO;
+            continue;
+        }
+
+        let mut acceleration_factor = gravity.acceleration_factor;
+        if let (KCCMovementMode::Swim, Some((buoyancy, submerged))) = (mode, swimming) {
+            // Water pushes back against gravity and slows the character down
+            acceleration_factor *= 1.0 - buoyancy.buoyancy * submerged.fraction;
+            gravity.current_velocity *= (1.0 - buoyancy.drag * submerged.fraction * dt).max(0.0);
+        }
+
         let current_speed = gravity.current_velocity.length();
         if current_speed >= gravity.terminal_velocity {
             // Decelerate to terminal velocity
@@ -257,7 +482,7 @@ pub fn gravity_system(
             continue;
         }
 
-        let delta_velocity = gravity.direction * gravity.acceleration_factor * dt;
+        let delta_velocity = gravity.direction * acceleration_factor * dt;
         let new_velocity = gravity.current_velocity + delta_velocity;
 
         gravity.current_velocity = if new_velocity.length() > gravity.terminal_velocity {
@@ -0,0 +1,110 @@
+//! # Push Module
+//!
+//! Interaction between kinematic characters and dynamic rigid bodies:
+//!
+//! - Characters walking into a dynamic body push it along, lighter bodies more than heavier ones.
+//! - Characters with [`KCCPush::pushable`] set are pushed back by the bodies moving into them.
+
+use avian3d::prelude::*;
+use bevy::{ecs::system::SystemParam, prelude::*, utils::HashSet};
+
+/// Component that lets the character push dynamic bodies around, and be pushed by them.
+#[derive(Component, Reflect, Debug)]
+#[reflect(Component)]
+pub struct KCCPush {
+    /// Mass of the character, weighed against the mass of the bodies it pushes.
+    pub mass: f32,
+    /// Whether dynamic bodies moving into the character push it away.
+    pub pushable: bool,
+}
+
+impl Default for KCCPush {
+    fn default() -> Self {
+        Self {
+            mass: 70.0,
+            pushable: true,
+        }
+    }
+}
+
+/// System parameter used by the controller systems to push dynamic bodies and be pushed by them.
+#[derive(SystemParam)]
+pub struct KCCBodies<'w, 's> {
+    commands: Commands<'w, 's>,
+    bodies_q: Query<
+        'w,
+        's,
+        (
+            &'static RigidBody,
+            &'static LinearVelocity,
+            &'static ComputedMass,
+            &'static Position,
+            Option<&'static mut ExternalImpulse>,
+        ),
+    >,
+    collider_parents_q: Query<'w, 's, &'static ColliderParent>,
+}
+
+impl KCCBodies<'_, '_> {
+    /// The dynamic body `collider` is attached to, if any.
+    fn dynamic_body(&self, collider: Entity) -> Option<Entity> {
+        let body = self
+            .collider_parents_q
+            .get(collider)
+            .map_or(collider, ColliderParent::get);
+        let (rigid_body, ..) = self.bodies_q.get(body).ok()?;
+        rigid_body.is_dynamic().then_some(body)
+    }
+
+    /// Pushes the body of `collider` along `direction`, as a character of `mass` moving at
+    /// `velocity` walks into it.
+    /// Bodies lighter than the character are brought up to its speed, heavier ones part of the way.
+    pub fn push(&mut self, collider: Entity, direction: Vec3, velocity: Vec3, mass: f32) {
+        let Some(body) = self.dynamic_body(collider) else {
+            return;
+        };
+        let Ok((_, body_velocity, body_mass, _, impulse)) = self.bodies_q.get_mut(body) else {
+            return;
+        };
+        let speed = velocity.dot(direction) - body_velocity.dot(direction);
+        if speed <= 0.0 {
+            return;
+        }
+        let push = direction * speed * body_mass.value().min(mass);
+        match impulse {
+            Some(mut impulse) => {
+                impulse.apply_impulse(push);
+            }
+            None => {
+                self.commands
+                    .entity(body)
+                    .insert(ExternalImpulse::new(push));
+            }
+        }
+    }
+
+    /// Velocity a character of `mass` at `position` is pushed at by the bodies of `colliders`.
+    /// Every body pushes with its speed towards the character, the heavier it is the more of it.
+    pub fn pushed_velocity(
+        &self,
+        colliders: impl IntoIterator<Item = Entity>,
+        position: Vec3,
+        mass: f32,
+    ) -> Vec3 {
+        let bodies: HashSet<Entity> = colliders
+            .into_iter()
+            .filter_map(|collider| self.dynamic_body(collider))
+            .collect();
+        bodies
+            .into_iter()
+            .filter_map(|body| {
+                let (_, body_velocity, body_mass, body_position, _) =
+                    self.bodies_q.get(body).ok()?;
+                let direction = (position - body_position.0).normalize_or_zero();
+                let speed = body_velocity.dot(direction).max(0.0);
+                let share = body_mass.value() / (body_mass.value() + mass);
+                Some(direction * speed * share)
+            })
+            .sum()
+    }
+}
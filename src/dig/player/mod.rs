@@ -1,24 +1,57 @@
-use avian3d::{
-    math::{Quaternion, Vector},
-    prelude::*,
-};
+use avian3d::prelude::*;
 use bevy::{color::palettes::css, prelude::*};
 use camera::{FpsCamera, FpsCameraPlugin};
 use kcc::{
-    plugin, KCCFloorDetection, KCCGravity, KCCGrounded, KCCSlope, KinematicCharacterController,
+    plugin, KCCBuoyancy, KCCClimb, KCCCollisionBackend, KCCCrouch, KCCFloorDetection, KCCGravity,
+    KCCGrounded, KCCInterpolation, KCCMovementMode, KCCMovementSettings, KCCPush, KCCSlope,
+    KinematicCharacterController,
 };
 use movement::*;
 
-use crate::indexed_camera::IndexedCamera;
+use crate::{
+    dig::{
+        input::InputSource,
+        terrain::{Submerged, VoxelPointer},
+    },
+    generation::GenerationFocus,
+    indexed_camera::IndexedCamera,
+};
 
 pub mod camera;
-mod kcc;
+pub mod kcc;
 mod movement;
 
+/// Height of the camera above the player's center when standing and crouching.
+const STANDING_EYE_HEIGHT: f32 = 0.6;
+const CROUCHING_EYE_HEIGHT: f32 = 0.3;
+
 pub struct DigPlayerPlugin;
 impl Plugin for DigPlayerPlugin {
     fn build(&self, app: &mut App) {
-        app.add_plugins((PlayerMovementPlugin, FpsCameraPlugin, plugin));
+        app.add_plugins((PlayerMovementPlugin, FpsCameraPlugin, plugin))
+            .add_systems(Update, update_eye_height);
+    }
+}
+
+/// Moves the camera down while the player crouches.
+fn update_eye_height(
+    time: Res<Time>,
+    players_q: Query<&KCCCrouch>,
+    mut cameras_q: Query<(&mut Transform, &Parent), With<FpsCamera>>,
+) {
+    for (mut transform, parent) in cameras_q.iter_mut() {
+        let Ok(crouch) = players_q.get(parent.get()) else {
+            continue;
+        };
+        let target = if crouch.crouching {
+            CROUCHING_EYE_HEIGHT
+        } else {
+            STANDING_EYE_HEIGHT
+        };
+        transform.translation.y = transform
+            .translation
+            .y
+            .lerp(target, 1. - (-15. * time.delta_secs()).exp());
     }
 }
 
@@ -47,52 +80,56 @@ impl Plugin for DigPlayerPlugin {
     commands.entity(player).with_child((
         IndexedCamera::new(0),
         FpsCamera::new(0.1),
-        Transform::from_xyz(0.0, 0.6, 0.0),
-        RayCaster::new(Vec3::ZERO, -Dir3::Z)
-            .with_query_filter(SpatialQueryFilter::from_excluded_entities([player]))
-            .with_max_hits(1)
-            .with_max_distance(30.),
+        Transform::from_xyz(0.0, STANDING_EYE_HEIGHT, 0.0),
     ));
 }
  */
 
+/// Spawns a player controlled by `source`, with its own camera switched to by `camera_index`.
 pub fn spawn_player(
     commands: &mut Commands,
     meshes: &mut ResMut<Assets<Mesh>>,
     materials: &mut ResMut<Assets<StandardMaterial>>,
-) {
+    source: InputSource,
+    camera_index: u32,
+    translation: Vec3,
+) -> Entity {
     let player = commands
         .spawn((
             RigidBody::Kinematic,
             KCCGravity::default(),
-            ShapeCaster::new(
-                Capsule3d::new(0.4, 0.8),
-                Vector::ZERO,
-                Quaternion::default(),
-                Dir3::NEG_Y,
-            ),
             KinematicCharacterController::default(),
             KCCGrounded::default(),
             KCCFloorDetection::default(),
             KCCSlope::default(),
+            // The terrain trimeshes lag behind edits and can be fallen through
+            KCCCollisionBackend::Voxels,
+            KCCBuoyancy::default(),
+            KCCCrouch::default(),
+            KCCClimb::default(),
+            KCCPush::default(),
+            KCCInterpolation::default(),
+            KCCMovementMode::default(),
+            KCCMovementSettings::default(),
+            Submerged::new(1.6),
             Mesh3d(meshes.add(Capsule3d {
                 radius: 0.4,
                 half_length: 0.4,
             })),
             MeshMaterial3d(materials.add(Color::from(css::DARK_CYAN))),
             LockedAxes::ROTATION_LOCKED,
-            Name::new("CurrentPlayer"),
-            Transform::from_xyz(0.0, 1.5, 0.0),
+            source,
+            VoxelPointer::default(),
+            Name::new("Player"),
+            Transform::from_translation(translation),
         ))
         .id();
 
     commands.entity(player).with_child((
-        IndexedCamera::new(0),
+        IndexedCamera::new(camera_index),
         FpsCamera::new(0.1),
-        Transform::from_xyz(0.0, 0.6, 0.0),
-        RayCaster::new(Vec3::ZERO, -Dir3::Z)
-            .with_query_filter(SpatialQueryFilter::from_excluded_entities([player]))
-            .with_max_hits(1)
-            .with_max_distance(30.),
+        GenerationFocus,
+        Transform::from_xyz(0.0, STANDING_EYE_HEIGHT, 0.0),
     ));
+    player
 }
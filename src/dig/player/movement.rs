@@ -4,6 +4,8 @@ use avian3d::{
 };
 use bevy::prelude::*;
 
+use crate::dig::input::{Action, ActionState};
+
 pub struct PlayerMovementPlugin;
 
 #[derive(Component)]
@@ -94,8 +96,8 @@ fn handle_spring(
 }
 
 fn handle_movement(
-    keyboard: Res<ButtonInput<KeyCode>>,
     mut query: Query<(
+        &ActionState,
         &Transform,
         &mut LinearVelocity,
         &PlayerMovement,
@@ -103,20 +105,8 @@ fn handle_movement(
     )>,
     time: Res<Time>,
 ) {
-    let mut move_input = Vec2::ZERO;
-    if keyboard.pressed(KeyCode::KeyA) {
-        move_input.x = -1.;
-    }
-    if keyboard.pressed(KeyCode::KeyD) {
-        move_input.x = 1.;
-    }
-    if keyboard.pressed(KeyCode::KeyW) {
-        move_input.y = 1.;
-    }
-    if keyboard.pressed(KeyCode::KeyS) {
-        move_input.y = -1.;
-    }
-    for (transform, mut vel, movement, maybe_grounded) in query.iter_mut() {
+    for (actions, transform, mut vel, movement, maybe_grounded) in query.iter_mut() {
+        let move_input = actions.movement;
         let mut world_input = transform.forward() * move_input.y + transform.right() * move_input.x;
         if world_input != Vec3::ZERO {
             world_input = world_input.normalize();
@@ -133,11 +123,10 @@ fn handle_movement(
 }
 
 fn handle_jump(
-    keyboard: Res<ButtonInput<KeyCode>>,
-    mut query: Query<(&mut LinearVelocity, &PlayerMovement), With<Grounded>>,
+    mut query: Query<(&ActionState, &mut LinearVelocity, &PlayerMovement), With<Grounded>>,
 ) {
-    if keyboard.just_pressed(KeyCode::Space) {
-        for (mut vel, pm) in query.iter_mut() {
+    for (actions, mut vel, pm) in query.iter_mut() {
+        if actions.just_pressed(Action::Jump) {
             vel.0.y = pm.jump_force;
         }
     }
@@ -0,0 +1,189 @@
+use avian3d::prelude::{Collider, ColliderAabb, LinearVelocity, RigidBody};
+use bevy::{
+    prelude::*,
+    tasks::{block_on, futures_lite::future, AsyncComputeTaskPool, Task},
+    utils::HashMap,
+};
+
+use crate::{
+    dig::player::kcc::{KCCGravity, KinematicCharacterController},
+    generation::ChunkSettings,
+};
+
+use super::{update_mesh, ChunkMesh, ChunkMeshReady, VOXEL_SCALE};
+
+/// Distances, in world units, between a chunk and the closest dynamic or kinematic body at which
+/// the chunk's collider is built and dropped.
+/// Dropping it further away than it is built keeps a body moving around a single distance from
+/// rebuilding it over and over.
+/// Both distances grow with the speed of the body, so colliders, which are built in the
+/// background, are ready before fast bodies reach the chunk.
+#[derive(Resource, Debug, Clone, Copy)]
+pub struct ChunkColliderDistances {
+    pub build: f32,
+    pub drop: f32,
+    /// Seconds of movement added to the distances, at the speed of each body.
+    pub lookahead: f32,
+}
+
+impl Default for ChunkColliderDistances {
+    fn default() -> Self {
+        Self {
+            build: 2.,
+            drop: 4.,
+            lookahead: 0.5,
+        }
+    }
+}
+
+/// Added to the chunks close enough to a body to need a collider.
+#[derive(Component, Default)]
+pub struct ChunkCollider {
+    /// Generation of the mesh the current collider was built from.
+    generation: Option<u64>,
+}
+
+/// Colliders being built in the background, at most one per chunk.
+#[derive(Resource, Default)]
+struct ChunkColliderTasks(HashMap<Entity, (u64, Task<Option<Collider>>)>);
+
+pub struct ChunkCollidersPlugin;
+impl Plugin for ChunkCollidersPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ChunkColliderDistances>()
+            .init_resource::<ChunkColliderTasks>()
+            .add_systems(
+                Update,
+                (
+                    update_collider_proximity,
+                    build_chunk_colliders,
+                    poll_chunk_colliders,
+                )
+                    .chain()
+                    .after(update_mesh),
+            );
+    }
+}
+
+fn update_collider_proximity(
+    mut commands: Commands,
+    distances: Res<ChunkColliderDistances>,
+    settings: Res<ChunkSettings>,
+    mut tasks: ResMut<ChunkColliderTasks>,
+    mut chunks_q: Query<(Entity, &Transform, &mut ChunkMesh, Has<ChunkCollider>)>,
+    bodies_q: Query<(&RigidBody, &ColliderAabb, Option<&LinearVelocity>)>,
+    characters_q: Query<(
+        &Transform,
+        &KinematicCharacterController,
+        Option<&KCCGravity>,
+    )>,
+) {
+    // Bounds of every moving body along with how far it gets in the lookahead time. Characters
+    // have no physics collider of their own, so their bounds come from their controller.
+    let bodies: Vec<(Vec3, Vec3, f32)> = bodies_q
+        .iter()
+        .filter(|(body, ..)| !body.is_static())
+        .map(|(_, aabb, velocity)| {
+            let speed = velocity.map_or(0., |velocity| velocity.length());
+            (aabb.min, aabb.max, speed * distances.lookahead)
+        })
+        .chain(characters_q.iter().map(|(transform, controller, gravity)| {
+            let aabb = controller
+                .collider
+                .aabb(transform.translation, transform.rotation);
+            let velocity = controller.velocity
+                + gravity.map_or(Vec3::ZERO, |gravity| gravity.current_velocity);
+            (aabb.min, aabb.max, velocity.length() * distances.lookahead)
+        }))
+        .collect();
+
+    // Meshes span the chunk plus the voxels shared with the next one
+    let chunk_size = Vec3::splat((settings.width + 1) as f32 * VOXEL_SCALE);
+    for (entity, transform, mut chunk, has_collider) in chunks_q.iter_mut() {
+        let min = transform.translation;
+        let max = min + chunk_size;
+        let closest = bodies
+            .iter()
+            .map(|(body_min, body_max, lookahead)| {
+                (min - *body_max)
+                    .max(*body_min - max)
+                    .max(Vec3::ZERO)
+                    .length()
+                    - lookahead
+            })
+            .fold(f32::INFINITY, f32::min);
+        let needs_collider = if has_collider {
+            closest <= distances.drop
+        } else {
+            closest < distances.build
+        };
+        if needs_collider && !has_collider {
+            commands.entity(entity).insert(ChunkCollider::default());
+        } else if !needs_collider && has_collider {
+            commands
+                .entity(entity)
+                .remove::<(ChunkCollider, Collider)>();
+            tasks.0.remove(&entity);
+        }
+        // Chunks needing a collider are ready once it is built, the others as soon as their mesh
+        // is in place
+        if !needs_collider && chunk.ready_generation != Some(chunk.generation) {
+            chunk.ready_generation = Some(chunk.generation);
+            commands.send_event(ChunkMeshReady {
+                index: chunk.index,
+                generation: chunk.generation,
+            });
+        }
+    }
+}
+
+fn build_chunk_colliders(
+    meshes: Res<Assets<Mesh>>,
+    mut tasks: ResMut<ChunkColliderTasks>,
+    chunks_q: Query<(Entity, &Mesh3d, &ChunkMesh, &ChunkCollider)>,
+) {
+    for (entity, mesh, chunk, collider) in chunks_q.iter() {
+        if collider.generation == Some(chunk.generation)
+            || tasks
+                .0
+                .get(&entity)
+                .is_some_and(|(generation, _)| *generation == chunk.generation)
+        {
+            continue;
+        }
+        let Some(mesh) = meshes.get(mesh) else {
+            continue;
+        };
+        let mesh = mesh.clone();
+        let task =
+            AsyncComputeTaskPool::get().spawn(async move { Collider::trimesh_from_mesh(&mesh) });
+        // Dropping the task of an outdated mesh cancels it
+        tasks.0.insert(entity, (chunk.generation, task));
+    }
+}
+
+fn poll_chunk_colliders(
+    mut commands: Commands,
+    mut tasks: ResMut<ChunkColliderTasks>,
+    mut chunks_q: Query<(&mut ChunkMesh, &mut ChunkCollider)>,
+) {
+    tasks.0.retain(|entity, (generation, task)| {
+        let Some(collider) = block_on(future::poll_once(task)) else {
+            return true;
+        };
+        let Ok((mut chunk, mut state)) = chunks_q.get_mut(*entity) else {
+            return false;
+        };
+        state.generation = Some(*generation);
+        chunk.ready_generation = Some(*generation);
+        match collider {
+            Some(collider) => commands.entity(*entity).insert(collider),
+            None => commands.entity(*entity).remove::<Collider>(),
+        };
+        commands.send_event(ChunkMeshReady {
+            index: chunk.index,
+            generation: *generation,
+        });
+        false
+    });
+}
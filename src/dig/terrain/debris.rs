@@ -0,0 +1,155 @@
+use std::{collections::VecDeque, f32::consts::PI};
+
+use avian3d::prelude::{
+    AngularVelocity, Collider, ColliderDisabled, LinearVelocity, Mass, RigidBody, RigidBodyDisabled,
+};
+use bevy::prelude::*;
+
+use super::{GroundMaterialHandle, VoxelsDug};
+
+/// Rubble thrown out of the terrain when it is dug.
+#[derive(Resource, Debug, Clone)]
+pub struct DebrisSettings {
+    pub enabled: bool,
+    /// How many dug voxels make up a single piece of debris.
+    pub voxels_per_piece: f32,
+    /// Most pieces a single dig can throw out.
+    pub max_pieces_per_dig: usize,
+    /// Most pieces alive at once, the oldest ones are reused past that.
+    pub max_pieces: usize,
+    /// Seconds a piece lives before it is put back in the pool.
+    pub lifetime: f32,
+    /// Average width of a piece, in world units.
+    pub piece_size: f32,
+}
+
+impl Default for DebrisSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            voxels_per_piece: 64.,
+            max_pieces_per_dig: 16,
+            max_pieces: 128,
+            lifetime: 8.,
+            piece_size: 0.2,
+        }
+    }
+}
+
+#[derive(Component)]
+pub struct Debris {
+    lifetime: Timer,
+}
+
+/// Pieces of debris, alive ones ordered from the oldest, and disabled ones waiting to be reused.
+#[derive(Resource, Default)]
+struct DebrisPool {
+    alive: VecDeque<Entity>,
+    free: Vec<Entity>,
+    mesh: Handle<Mesh>,
+}
+
+pub struct DebrisPlugin;
+impl Plugin for DebrisPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<DebrisSettings>()
+            .init_resource::<DebrisPool>()
+            .add_systems(Startup, setup_debris_mesh)
+            .add_systems(Update, (spawn_debris, expire_debris).chain());
+    }
+}
+
+fn setup_debris_mesh(mut pool: ResMut<DebrisPool>, mut meshes: ResMut<Assets<Mesh>>) {
+    pool.mesh = meshes.add(Cuboid::from_length(1.));
+}
+
+fn spawn_debris(
+    mut commands: Commands,
+    mut dug_r: EventReader<VoxelsDug>,
+    settings: Res<DebrisSettings>,
+    mut pool: ResMut<DebrisPool>,
+    ground_material: Res<GroundMaterialHandle>,
+) {
+    for dug in dug_r.read() {
+        if !settings.enabled {
+            continue;
+        }
+        let pieces = ((dug.voxels as f32 / settings.voxels_per_piece).ceil() as usize)
+            .min(settings.max_pieces_per_dig);
+        for i in 0..pieces {
+            // Spread the pieces evenly over the sphere, thrown outwards and upwards
+            let direction = fibonacci_sphere(i, pieces);
+            let variation = (i as f32 * 0.618_034).fract();
+            let transform =
+                Transform::from_translation(dug.world_pos + direction * dug.radius * 0.5)
+                    .with_rotation(Quat::from_axis_angle(direction, variation * PI))
+                    .with_scale(Vec3::splat(settings.piece_size * (0.75 + variation * 0.5)));
+            let velocity = LinearVelocity(direction * 2. + Vec3::Y * 2.);
+            let debris = Debris {
+                lifetime: Timer::from_seconds(settings.lifetime, TimerMode::Once),
+            };
+
+            let entity = if let Some(entity) = pool.free.pop() {
+                commands
+                    .entity(entity)
+                    .remove::<(RigidBodyDisabled, ColliderDisabled)>();
+                entity
+            } else if pool.alive.len() < settings.max_pieces {
+                commands
+                    .spawn((
+                        Mesh3d(pool.mesh.clone()),
+                        MeshMaterial3d(ground_material.0.clone()),
+                        RigidBody::Dynamic,
+                        Collider::cuboid(1., 1., 1.),
+                        Mass(0.5),
+                    ))
+                    .id()
+            } else if let Some(entity) = pool.alive.pop_front() {
+                entity
+            } else {
+                continue;
+            };
+            commands.entity(entity).insert((
+                transform,
+                Visibility::Inherited,
+                velocity,
+                AngularVelocity::ZERO,
+                debris,
+            ));
+            pool.alive.push_back(entity);
+        }
+    }
+}
+
+/// Puts the pieces that lived long enough back in the pool.
+fn expire_debris(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut pool: ResMut<DebrisPool>,
+    mut debris_q: Query<&mut Debris, Without<RigidBodyDisabled>>,
+) {
+    for mut debris in debris_q.iter_mut() {
+        debris.lifetime.tick(time.delta());
+    }
+    while let Some(&entity) = pool.alive.front() {
+        if !debris_q
+            .get(entity)
+            .is_ok_and(|debris| debris.lifetime.finished())
+        {
+            break;
+        }
+        pool.alive.pop_front();
+        commands
+            .entity(entity)
+            .insert((RigidBodyDisabled, ColliderDisabled, Visibility::Hidden));
+        pool.free.push(entity);
+    }
+}
+
+/// The `i`th of `count` points evenly spread over the unit sphere.
+fn fibonacci_sphere(i: usize, count: usize) -> Vec3 {
+    let y = 1. - (i as f32 + 0.5) / count as f32 * 2.;
+    let radius = (1. - y * y).sqrt();
+    let angle = i as f32 * PI * (3. - 5_f32.sqrt());
+    Vec3::new(angle.cos() * radius, y, angle.sin() * radius)
+}
@@ -0,0 +1,152 @@
+use avian3d::prelude::{
+    Collider, CollisionStarted, ExternalImpulse, RigidBody, SpatialQuery, SpatialQueryFilter,
+};
+use bevy::{prelude::*, utils::HashSet};
+
+use crate::voxel::chunks_manager::{ChunksManager, ChunksReader, PendingEdits};
+
+use super::{ChunksToGenerateQueue, RemeshPriority, VoxelsDug};
+
+/// Blows a crater in the terrain and pushes the dynamic bodies around it away.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct Explosion {
+    pub world_pos: Vec3,
+    /// Radius of the crater, in world units.
+    pub radius: f32,
+    /// Impulse given to a body at the center, fading out to nothing at `push_radius`.
+    pub impulse: f32,
+    /// How far from the center bodies are pushed, in world units.
+    pub push_radius: f32,
+}
+
+impl Explosion {
+    pub fn new(world_pos: Vec3, radius: f32, impulse: f32) -> Explosion {
+        Explosion {
+            world_pos,
+            radius,
+            impulse,
+            push_radius: radius * 2.,
+        }
+    }
+}
+
+/// How rough the edge of the craters is, as a fraction of their radius.
+#[derive(Resource, Debug, Clone, Copy)]
+pub struct ExplosionSettings {
+    pub roughness: f32,
+}
+
+impl Default for ExplosionSettings {
+    fn default() -> Self {
+        Self { roughness: 0.25 }
+    }
+}
+
+/// Makes a projectile explode, and despawn, as soon as it hits something.
+#[derive(Component, Debug, Clone, Copy)]
+pub struct ExplodeOnImpact {
+    pub radius: f32,
+    pub impulse: f32,
+}
+
+impl Default for ExplodeOnImpact {
+    fn default() -> Self {
+        Self {
+            radius: 2.,
+            impulse: 20.,
+        }
+    }
+}
+
+pub struct ExplosionsPlugin;
+impl Plugin for ExplosionsPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ExplosionSettings>()
+            .add_event::<Explosion>()
+            .add_systems(Update, (explode_on_impact, explode).chain());
+    }
+}
+
+/// Projectiles explode when they collide with another body, or enter the voxels of a chunk whose
+/// collider isn't built yet.
+fn explode_on_impact(
+    mut commands: Commands,
+    mut collisions_r: EventReader<CollisionStarted>,
+    mut explosion_w: EventWriter<Explosion>,
+    chunks: ChunksReader,
+    projectiles_q: Query<(Entity, &ExplodeOnImpact, &GlobalTransform)>,
+) {
+    let hit: HashSet<Entity> = collisions_r
+        .read()
+        .flat_map(|CollisionStarted(a, b)| [*a, *b])
+        .collect();
+    for (entity, projectile, transform) in projectiles_q.iter() {
+        let pos = transform.translation();
+        let voxel = chunks.world_pos_to_voxel_pos(pos).round().as_ivec3();
+        if !hit.contains(&entity) && !chunks.is_solid(voxel) {
+            continue;
+        }
+        explosion_w.send(Explosion::new(pos, projectile.radius, projectile.impulse));
+        commands.entity(entity).despawn_recursive();
+    }
+}
+
+fn explode(
+    mut explosion_r: EventReader<Explosion>,
+    mut dug_w: EventWriter<VoxelsDug>,
+    settings: Res<ExplosionSettings>,
+    mut chunks_manager: ChunksManager,
+    mut edits: ResMut<PendingEdits>,
+    mut queue: ResMut<ChunksToGenerateQueue>,
+    spatial_query: SpatialQuery,
+    mut bodies_q: Query<(&RigidBody, &GlobalTransform, Option<&mut ExternalImpulse>)>,
+    mut commands: Commands,
+) {
+    for explosion in explosion_r.read() {
+        // Every explosion at a different place gets a different crater
+        let seed = explosion.world_pos.x.to_bits()
+            ^ explosion.world_pos.y.to_bits().rotate_left(11)
+            ^ explosion.world_pos.z.to_bits().rotate_left(22);
+        let edit = chunks_manager.dig_crater(
+            explosion.world_pos,
+            explosion.radius,
+            settings.roughness,
+            seed,
+        );
+        edits.register(&edit);
+        for index in edit.remesh {
+            queue.push(index, RemeshPriority::PlayerEdit);
+        }
+        dug_w.send(VoxelsDug {
+            world_pos: explosion.world_pos,
+            radius: explosion.radius * (1. + settings.roughness),
+            voxels: edit.voxels,
+        });
+
+        let in_range = spatial_query.shape_intersections(
+            &Collider::sphere(explosion.push_radius),
+            explosion.world_pos,
+            Quat::IDENTITY,
+            &SpatialQueryFilter::default(),
+        );
+        for entity in in_range {
+            let Ok((body, transform, impulse)) = bodies_q.get_mut(entity) else {
+                continue;
+            };
+            if !body.is_dynamic() {
+                continue;
+            }
+            let offset = transform.translation() - explosion.world_pos;
+            let falloff = (1. - offset.length() / explosion.push_radius).max(0.);
+            let push = offset.normalize_or(Vec3::Y) * explosion.impulse * falloff;
+            match impulse {
+                Some(mut impulse) => {
+                    impulse.apply_impulse(push);
+                }
+                None => {
+                    commands.entity(entity).insert(ExternalImpulse::new(push));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use std::time::Duration;
+
+use bevy::{prelude::*, utils::HashSet};
+
+use crate::voxel::{
+    chunks_manager::{ChunksManager, ChunksReader},
+    VoxelKind, VoxelLayers,
+};
+
+use super::VoxelsDug;
+
+/// Most simulation ticks run in a single frame, so a slow frame doesn't snowball.
+const MAX_TICKS_PER_FRAME: u32 = 4;
+
+/// Sand and gravel falling and sliding until they settle, simulated as a cellular automaton.
+/// Only the voxels around recent digs, and around the ones that moved, are looked at.
+#[derive(Resource)]
+pub struct GranularSimulation {
+    tick: Timer,
+    ticks: u32,
+    /// Positions to look at on the next tick, in the voxel space of the world.
+    active: HashSet<IVec3>,
+}
+
+impl GranularSimulation {
+    pub fn new(ticks_per_second: f32) -> GranularSimulation {
+        GranularSimulation {
+            tick: Timer::new(
+                Duration::from_secs_f32(1. / ticks_per_second),
+                TimerMode::Repeating,
+            ),
+            ticks: 0,
+            active: HashSet::new(),
+        }
+    }
+
+    /// Looks at the voxel again on the next tick, for when what held it was taken away.
+    pub fn wake(&mut self, pos: IVec3) {
+        self.active.insert(pos);
+    }
+
+    /// Whether every voxel has settled.
+    pub fn is_settled(&self) -> bool {
+        self.active.is_empty()
+    }
+}
+
+impl Default for GranularSimulation {
+    fn default() -> Self {
+        GranularSimulation::new(20.)
+    }
+}
+
+pub struct GranularPlugin;
+impl Plugin for GranularPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<GranularSimulation>()
+            .init_resource::<VoxelLayers>()
+            .register_type::<VoxelKind>()
+            .register_type::<VoxelLayers>()
+            .add_systems(Update, (wake_dug_voxels, simulate_granular).chain());
+    }
+}
+
+/// The voxels around a dig may have lost their support.
+fn wake_dug_voxels(
+    mut dug_r: EventReader<VoxelsDug>,
+    mut simulation: ResMut<GranularSimulation>,
+    chunks: ChunksReader,
+) {
+    for dug in dug_r.read() {
+        let center = chunks.world_pos_to_voxel_pos(dug.world_pos);
+        let radius = ChunksManager::world_length_to_voxel_length(dug.radius);
+        let inner = (radius - 1.).max(0.);
+        let outer = radius + 2.;
+        let min = (center - outer).floor().as_ivec3();
+        let max = (center + outer).ceil().as_ivec3();
+        for z in min.z..=max.z {
+            for y in min.y..=max.y {
+                for x in min.x..=max.x {
+                    let pos = IVec3::new(x, y, z);
+                    let distance = pos.as_vec3().distance(center);
+                    if distance >= inner && distance <= outer {
+                        simulation.active.insert(pos);
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub(super) fn simulate_granular(
+    time: Res<Time>,
+    mut simulation: ResMut<GranularSimulation>,
+    mut chunks_manager: ChunksManager,
+) {
+    simulation.tick.tick(time.delta());
+    for _ in 0..simulation
+        .tick
+        .times_finished_this_tick()
+        .min(MAX_TICKS_PER_FRAME)
+    {
+        if simulation.is_settled() {
+            return;
+        }
+        step(&mut simulation, &mut chunks_manager);
+    }
+}
+
+/// Moves every active granular voxel that can fall by one voxel, lowest ones first so columns fall
+/// together.
+fn step(simulation: &mut GranularSimulation, chunks_manager: &mut ChunksManager) {
+    let mut cells: Vec<IVec3> = simulation.active.drain().collect();
+    cells.sort_unstable_by_key(|pos| pos.y);
+    // Alternate the order sides are tried in so piles don't lean one way
+    simulation.ticks = simulation.ticks.wrapping_add(1);
+    let sides = [IVec3::X, IVec3::Z, IVec3::NEG_X, IVec3::NEG_Z];
+    let first_side = simulation.ticks as usize % sides.len();
+
+    for pos in cells {
+        let Some(repose_drop) = chunks_manager
+            .voxel_kind(pos)
+            .and_then(|kind| kind.repose_drop())
+        else {
+            continue;
+        };
+        let below = pos - IVec3::Y;
+        let target = if chunks_manager.is_free(below) {
+            Some(below)
+        } else {
+            (0..sides.len())
+                .map(|i| pos + sides[(first_side + i) % sides.len()])
+                .find(|side| {
+                    chunks_manager.is_free(*side)
+                        && (1..=repose_drop as i32)
+                            .all(|drop| chunks_manager.is_free(*side - IVec3::Y * drop))
+                })
+                .map(|side| side - IVec3::Y)
+        };
+        let Some(target) = target else {
+            continue;
+        };
+        chunks_manager.move_voxel(pos, target);
+        // The moved voxel keeps falling, and whatever it supported may follow
+        simulation.active.insert(target);
+        for z in -1..=1 {
+            for y in 0..=1 {
+                for x in -1..=1 {
+                    simulation.active.insert(pos + IVec3::new(x, y, z));
+                }
+            }
+        }
+    }
+}
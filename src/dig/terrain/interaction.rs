@@ -1,20 +1,41 @@
-use avian3d::prelude::{Collider, Mass, RayCaster, RayHits, RigidBody};
+use avian3d::prelude::{Collider, Mass, RigidBody};
 use bevy::prelude::*;
 
-use crate::{dig::player::camera::FpsCamera, voxel::chunks_manager::ChunksManager};
+use crate::{
+    dig::{
+        input::{Action, ActionState},
+        player::camera::FpsCamera,
+    },
+    voxel::chunks_manager::{ChunksManager, PendingEdits},
+};
 
-use super::VOXEL_SCALE;
+use super::{
+    ChunkMesh, ChunksToGenerateQueue, ExplodeOnImpact, RemeshPriority, VoxelsDug, VOXEL_SCALE,
+};
 
-#[derive(Resource)]
-pub struct VoxelPointerSize(f32);
+/// How far from the camera the terrain can be pointed at.
+const POINTER_MAX_DISTANCE: f32 = 30.;
 
-#[derive(Resource)]
-pub struct PointerPosition(pub Vec3);
+/// Where a player points at the terrain, and the size of their digging brush.
+#[derive(Component, Debug, Clone, Copy)]
+pub struct VoxelPointer {
+    pub position: Option<Vec3>,
+    pub size: f32,
+}
+
+impl Default for VoxelPointer {
+    fn default() -> Self {
+        Self {
+            position: None,
+            size: 5.,
+        }
+    }
+}
 
 pub struct VoxelInteractionPlugin;
 impl Plugin for VoxelInteractionPlugin {
     fn build(&self, app: &mut App) {
-        app.insert_resource(VoxelPointerSize(5.)).add_systems(
+        app.add_systems(
             Update,
             (
                 modify_voxels,
@@ -27,70 +48,106 @@ impl Plugin for VoxelInteractionPlugin {
 }
 
 fn modify_voxels(
-    keys: Res<ButtonInput<KeyCode>>,
-    mouse_buttons: Res<ButtonInput<MouseButton>>,
+    players_q: Query<(&ActionState, &VoxelPointer)>,
     mut chunks_manager: ChunksManager,
-    pointer_pos: Option<Res<PointerPosition>>,
-    voxel_size: Res<VoxelPointerSize>,
+    mut edits: ResMut<PendingEdits>,
+    mut queue: ResMut<ChunksToGenerateQueue>,
+    mut dug_w: EventWriter<VoxelsDug>,
     mut gizmos: Gizmos,
 ) {
-    let Some(pos) = pointer_pos else {
-        return;
-    };
-    gizmos.sphere(
-        Isometry3d::from_translation(pos.0),
-        voxel_size.0,
-        Color::WHITE,
-    );
-    if mouse_buttons.just_pressed(MouseButton::Left) {
-        chunks_manager.dig_sphere(pos.0, voxel_size.0);
+    let mut affected = Vec::new();
+    for (actions, pointer) in players_q.iter() {
+        let Some(pos) = pointer.position else {
+            continue;
+        };
+        gizmos.sphere(
+            Isometry3d::from_translation(pos),
+            pointer.size,
+            Color::WHITE,
+        );
+        if actions.just_pressed(Action::Dig) {
+            let edit = chunks_manager.dig_sphere(pos, pointer.size);
+            edits.register(&edit);
+            affected.extend(edit.remesh);
+            dug_w.send(VoxelsDug {
+                world_pos: pos,
+                radius: pointer.size,
+                voxels: edit.voxels,
+            });
+        }
+        if actions.just_pressed(Action::Build) {
+            let edit = chunks_manager.build_sphere(pos, pointer.size);
+            edits.register(&edit);
+            affected.extend(edit.remesh);
+        }
     }
-    if keys.just_pressed(KeyCode::KeyB) {
-        chunks_manager.build_sphere(pos.0, voxel_size.0);
+    // Player edits are remeshed before any background generation
+    for index in affected {
+        queue.push(index, RemeshPriority::PlayerEdit);
     }
 }
 
 fn spawn_sphere(
     mut commands: Commands,
-    keys: Res<ButtonInput<KeyCode>>,
+    players_q: Query<(&ActionState, &VoxelPointer)>,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
-    pointer_pos: Option<Res<PointerPosition>>,
 ) {
-    let Some(pos) = pointer_pos else {
-        return;
-    };
-    if keys.just_pressed(KeyCode::KeyR) {
-        commands.spawn((
+    for (actions, pointer) in players_q.iter() {
+        let Some(pos) = pointer.position else {
+            continue;
+        };
+        let explosive = actions.just_pressed(Action::ThrowExplosive);
+        if !explosive && !actions.just_pressed(Action::Throw) {
+            continue;
+        }
+        let mut sphere = commands.spawn((
             Mesh3d(meshes.add(Sphere::new(1.))),
             MeshMaterial3d(materials.add(StandardMaterial::default())),
             Collider::sphere(1.),
             Mass(1.),
             RigidBody::Dynamic,
-            Transform::from_translation(pos.0 + Vec3::Y * 10.),
+            Transform::from_translation(pos + Vec3::Y * 10.),
         ));
+        if explosive {
+            sphere.insert(ExplodeOnImpact::default());
+        }
     }
 }
 
+/// Points at the terrain in front of the active cameras, for the player each camera belongs to.
+/// The render meshes are cast against since far away chunks have no collider.
 fn handle_fps_pointer(
-    raycast_q: Query<(&GlobalTransform, &RayCaster, &RayHits), With<FpsCamera>>,
-    mut commands: Commands,
+    camera_q: Query<(Entity, &Camera, &GlobalTransform, Option<&Parent>), With<FpsCamera>>,
+    terrain_q: Query<(), With<ChunkMesh>>,
+    mut pointers_q: Query<&mut VoxelPointer>,
+    mut ray_cast: MeshRayCast,
 ) {
-    for (gt, raycast, hits) in raycast_q.iter() {
-        let Some(hit) = hits.iter().next() else {
-            commands.remove_resource::<PointerPosition>();
+    let is_terrain = |entity| terrain_q.contains(entity);
+    for (entity, camera, gt, parent) in camera_q.iter() {
+        if !camera.is_active {
+            continue;
+        }
+        let player = parent.map_or(entity, Parent::get);
+        let Ok(mut pointer) = pointers_q.get_mut(player) else {
             continue;
         };
-        let pos: Vec3 = gt.translation() + raycast.origin + gt.forward() * hit.distance;
-        commands.insert_resource::<PointerPosition>(PointerPosition(pos));
+        let ray = Ray3d::new(gt.translation(), gt.forward());
+        pointer.position = ray_cast
+            .cast_ray(ray, &RayCastSettings::default().with_filter(&is_terrain))
+            .first()
+            .filter(|(_, hit)| hit.distance <= POINTER_MAX_DISTANCE)
+            .map(|(_, hit)| hit.point);
     }
 }
 
-fn modify_pointer_size(keys: Res<ButtonInput<KeyCode>>, mut voxel_size: ResMut<VoxelPointerSize>) {
-    if keys.pressed(KeyCode::KeyQ) {
-        voxel_size.0 = (voxel_size.0 - 0.2).max(2. * VOXEL_SCALE);
-    }
-    if keys.pressed(KeyCode::KeyE) {
-        voxel_size.0 += 0.2;
+fn modify_pointer_size(mut players_q: Query<(&ActionState, &mut VoxelPointer)>) {
+    for (actions, mut pointer) in players_q.iter_mut() {
+        if actions.pressed(Action::ShrinkBrush) {
+            pointer.size = (pointer.size - 0.2).max(2. * VOXEL_SCALE);
+        }
+        if actions.pressed(Action::GrowBrush) {
+            pointer.size += 0.2;
+        }
     }
 }
@@ -0,0 +1,131 @@
+use avian3d::prelude::{Collider, RigidBody};
+use bevy::{
+    prelude::*,
+    tasks::{block_on, futures_lite::future, AsyncComputeTaskPool, Task},
+    utils::HashSet,
+};
+
+use crate::{
+    generation::{build_field_mesh, field::VoxelField},
+    voxel::chunks_manager::{ChunksManager, VoxelsChanged},
+};
+
+use super::{
+    granular::{simulate_granular, GranularSimulation},
+    ChunksToGenerateQueue, GroundMaterialHandle, RemeshPriority,
+};
+
+/// How big, in voxels, the terrain breaking off from the world can be.
+/// Groups of voxels at least `max_width` wide on any axis, or holding more than `max_voxels`, are
+/// treated as anchored, which keeps the search for detached terrain bounded, so bigger overhangs
+/// stay hanging.
+#[derive(Resource, Debug, Clone, Copy)]
+pub struct IslandSettings {
+    pub max_width: i32,
+    pub max_voxels: usize,
+}
+
+impl Default for IslandSettings {
+    fn default() -> Self {
+        Self {
+            max_width: 64,
+            max_voxels: 8192,
+        }
+    }
+}
+
+/// Terrain that broke off from the world and fell as a rigid body.
+#[derive(Component)]
+pub struct TerrainIsland;
+
+/// Meshes and colliders of detached islands being built in the background.
+#[derive(Resource, Default)]
+struct IslandTasks(Vec<Task<Option<(Transform, Mesh, Collider)>>>);
+
+pub struct TerrainIslandsPlugin;
+impl Plugin for TerrainIslandsPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<IslandSettings>()
+            .init_resource::<IslandTasks>()
+            .add_systems(
+                Update,
+                (detach_islands.after(simulate_granular), spawn_islands),
+            );
+    }
+}
+
+/// Takes the voxels that lost their connection to the bottom of the world out of the chunks,
+/// after any edit of the terrain.
+fn detach_islands(
+    mut changed_r: EventReader<VoxelsChanged>,
+    settings: Res<IslandSettings>,
+    mut chunks_manager: ChunksManager,
+    mut queue: ResMut<ChunksToGenerateQueue>,
+    mut tasks: ResMut<IslandTasks>,
+    mut granular: ResMut<GranularSimulation>,
+) {
+    // The changed voxels may have been built without support, and the ones right next to them may
+    // have lost theirs
+    let seeds: HashSet<IVec3> = changed_r
+        .read()
+        .flat_map(|changed| {
+            let min = changed.min - IVec3::ONE;
+            let max = changed.max + IVec3::ONE;
+            (min.z..=max.z).flat_map(move |z| {
+                (min.y..=max.y).flat_map(move |y| (min.x..=max.x).map(move |x| IVec3::new(x, y, z)))
+            })
+        })
+        .collect();
+    if seeds.is_empty() {
+        return;
+    }
+    for island in chunks_manager.find_islands(seeds, settings.max_width, settings.max_voxels) {
+        for index in chunks_manager.remove_voxels(&island.voxels) {
+            queue.push(index, RemeshPriority::PlayerEdit);
+        }
+        // Sand and gravel resting on the island are left behind and fall on their own
+        for voxel in island.voxels.iter() {
+            for direction in [IVec3::Y, IVec3::X, IVec3::NEG_X, IVec3::Z, IVec3::NEG_Z] {
+                granular.wake(*voxel + direction);
+            }
+        }
+        let positions: Vec<IVec3> = island
+            .voxels
+            .iter()
+            .map(|voxel| *voxel - island.min)
+            .collect();
+        let width = (island.max - island.min).max_element() as usize + 1;
+        let field = VoxelField::from_positions(&positions, width);
+        // The mesh space starts one voxel before the island
+        let translation = chunks_manager.voxel_pos_to_world_pos(island.min.as_vec3() - Vec3::ONE);
+        tasks.0.push(AsyncComputeTaskPool::get().spawn(async move {
+            let mesh = build_field_mesh(&field, translation)?;
+            let collider = Collider::convex_decomposition_from_mesh(&mesh)?;
+            Some((Transform::from_translation(translation), mesh, collider))
+        }));
+    }
+}
+
+fn spawn_islands(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    ground_material: Res<GroundMaterialHandle>,
+    mut tasks: ResMut<IslandTasks>,
+) {
+    tasks.0.retain_mut(|task| {
+        let Some(result) = block_on(future::poll_once(task)) else {
+            return true;
+        };
+        if let Some((transform, mesh, collider)) = result {
+            commands.spawn((
+                transform,
+                Mesh3d(meshes.add(mesh)),
+                MeshMaterial3d(ground_material.0.clone()),
+                RigidBody::Dynamic,
+                collider,
+                TerrainIsland,
+            ));
+        }
+        false
+    });
+}
@@ -1,43 +1,153 @@
-use std::collections::VecDeque;
-
-use avian3d::prelude::{Collider, RigidBody};
+use avian3d::prelude::RigidBody;
 use bevy::{
     pbr::{ExtendedMaterial, MaterialExtension, OpaqueRendererMethod},
     prelude::*,
-    render::render_resource::{AsBindGroup, ShaderRef},
+    render::{
+        render_asset::RenderAssets,
+        render_resource::{AsBindGroup, AsBindGroupShaderType, ShaderRef, ShaderType},
+        texture::GpuImage,
+    },
+    utils::HashMap,
     window::PrimaryWindow,
 };
-use interaction::{PointerPosition, VoxelInteractionPlugin};
+use colliders::ChunkCollidersPlugin;
+use debris::DebrisPlugin;
+use explosions::ExplosionsPlugin;
+use granular::GranularPlugin;
+use interaction::VoxelInteractionPlugin;
+use islands::TerrainIslandsPlugin;
+use water::WaterPlugin;
 
 use crate::{
-    generation::{ChunkMeshGenerated, GpuReadbackPlugin, BUFFER_LEN_UNCOMPRESSED, CHUNK_WIDTH},
-    voxel::{chunks_manager::ChunksManager, VoxelChunk},
+    dig::input::InputSource,
+    generation::{ChunkMeshGenerated, ChunkSettings, GpuReadbackPlugin, PendingChunkMeshes},
+    voxel::{
+        chunks_manager::{ChunksManager, ChunksReader, EditTicket, PendingEdits, VoxelsChanged},
+        VoxelChunk, VoxelLayers,
+    },
 };
 
+mod colliders;
+mod debris;
+mod explosions;
+mod granular;
 mod interaction;
+mod islands;
+mod water;
+
+pub use explosions::ExplodeOnImpact;
+pub use interaction::VoxelPointer;
+pub use water::{Aquifer, Aquifers, Submerged};
 
 pub const VOXEL_SCALE: f32 = 0.25;
 
+/// The mesh and collider of a voxel chunk.
 #[derive(Component)]
-struct ChunkMesh {
+pub struct ChunkMesh {
     index: UVec3,
+    /// Generation of the voxels the mesh was built from.
+    generation: u64,
+    /// Generation last reported in a [`ChunkMeshReady`].
+    ready_generation: Option<u64>,
 }
 
+/// Sent once every queued chunk has its mesh and collider in place.
 #[derive(Event)]
 pub struct FinishedGenerating;
 
-#[derive(Resource)]
-pub struct ChunksToGenerateQueue(pub VecDeque<ChunksToGenerateQueueElement>);
-
-pub struct ChunksToGenerateQueueElement {
+/// Sent once a chunk's mesh, and its collider if a body is close enough to need one, have been
+/// replaced by ones built from `generation`.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct ChunkMeshReady {
     pub index: UVec3,
-    pub input_data: [bool; BUFFER_LEN_UNCOMPRESSED],
+    pub generation: u64,
+}
+
+/// Sent once every chunk modified by an edit has its mesh and collider in place.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct EditApplied(pub EditTicket);
+
+/// Sent after a sphere of terrain has been dug, the terrain around it may no longer be supported.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct VoxelsDug {
+    pub world_pos: Vec3,
+    pub radius: f32,
+    /// How many solid voxels were removed.
+    pub voxels: usize,
+}
+
+/// Why a chunk needs to be remeshed, higher priorities are generated first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RemeshPriority {
+    Background,
+    PlayerEdit,
+}
+
+struct QueuedChunk {
+    priority: RemeshPriority,
+    order: u64,
+}
+
+/// Chunks waiting for a new mesh.
+/// A chunk is only queued once, its voxels are read when the mesh generation actually starts.
+#[derive(Resource, Default)]
+pub struct ChunksToGenerateQueue {
+    chunks: HashMap<UVec3, QueuedChunk>,
+    next_order: u64,
+}
+
+impl ChunksToGenerateQueue {
+    /// Queues the chunk, or raises its priority if it is already queued.
+    pub fn push(&mut self, index: UVec3, priority: RemeshPriority) {
+        let order = self.next_order;
+        self.next_order += 1;
+        self.chunks
+            .entry(index)
+            .and_modify(|queued| queued.priority = queued.priority.max(priority))
+            .or_insert(QueuedChunk { priority, order });
+    }
+
+    /// Takes the chunk with the highest priority, closest to `focus` (in chunk space), out of the queue.
+    /// Chunks at equal priority and distance are generated in the order they were queued.
+    pub fn pop(&mut self, focus: Option<Vec3>) -> Option<UVec3> {
+        let distance = |index: &UVec3| {
+            focus.map_or(0., |focus| (index.as_vec3() + 0.5).distance_squared(focus))
+        };
+        let (&index, _) = self.chunks.iter().min_by(|(a_index, a), (b_index, b)| {
+            b.priority
+                .cmp(&a.priority)
+                .then(distance(a_index).total_cmp(&distance(b_index)))
+                .then(a.order.cmp(&b.order))
+        })?;
+        self.chunks.remove(&index);
+        Some(index)
+    }
+
+    pub fn len(&self) -> usize {
+        self.chunks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+}
+
+pub(crate) struct DigTerrainPlugin {
+    pub chunk_settings: ChunkSettings,
 }
 
-pub(crate) struct DigTerrainPlugin;
 impl Plugin for DigTerrainPlugin {
     fn build(&self, app: &mut App) {
-        app.add_plugins(VoxelInteractionPlugin)
+        app.insert_resource(self.chunk_settings)
+            .add_plugins((
+                VoxelInteractionPlugin,
+                ChunkCollidersPlugin,
+                TerrainIslandsPlugin,
+                DebrisPlugin,
+                GranularPlugin,
+                WaterPlugin,
+                ExplosionsPlugin,
+            ))
             .add_plugins(
                 (
                     GpuReadbackPlugin,
@@ -45,103 +155,165 @@ impl Plugin for DigTerrainPlugin {
                 ), //  MaterialPlugin::<GroundMaterial>::default(),
             )
             .add_event::<FinishedGenerating>()
-            .insert_resource(ChunksToGenerateQueue(VecDeque::new()))
-            .add_systems(Update, (handle_voxel_changes, update_mesh));
+            .add_event::<ChunkMeshReady>()
+            .add_event::<EditApplied>()
+            .add_event::<VoxelsDug>()
+            .add_event::<VoxelsChanged>()
+            .init_resource::<ChunksToGenerateQueue>()
+            .init_resource::<PendingEdits>()
+            .add_systems(Startup, setup_ground_material)
+            .add_systems(
+                Update,
+                (
+                    handle_voxel_changes,
+                    update_mesh,
+                    track_edits.after(update_mesh),
+                ),
+            );
     }
 }
 
-pub fn spawn_terrain(mut chunks_manager: ChunksManager) {
-    chunks_manager.create_chunks(UVec3::new(3, 3, 3), VOXEL_SCALE);
+pub fn spawn_terrain(mut chunks_manager: ChunksManager, layers: Res<VoxelLayers>) {
+    chunks_manager.create_chunks(UVec3::new(3, 3, 3), VOXEL_SCALE, &layers);
+}
+
+/// The material shared by every terrain chunk, edit it to change the ground textures.
+#[derive(Resource)]
+pub struct GroundMaterialHandle(pub Handle<ExtendedMaterial<StandardMaterial, GroundMaterial>>);
+
+fn setup_ground_material(
+    mut commands: Commands,
+    mut ground_materials: ResMut<Assets<ExtendedMaterial<StandardMaterial, GroundMaterial>>>,
+) {
+    let handle = ground_materials.add(ExtendedMaterial {
+        base: StandardMaterial {
+            base_color: Color::WHITE,
+            opaque_render_method: OpaqueRendererMethod::Auto,
+            ..Default::default()
+        },
+        extension: GroundMaterial::default(),
+    });
+    commands.insert_resource(GroundMaterialHandle(handle));
 }
 
 fn handle_voxel_changes(
-    mut set: ParamSet<(Query<Entity, Changed<VoxelChunk>>, ChunksManager)>,
+    changed_q: Query<&VoxelChunk, Changed<VoxelChunk>>,
     mut queue: ResMut<ChunksToGenerateQueue>,
 ) {
-    let changed: Vec<Entity> = set.p0().iter().collect();
-    for chunk_entity in changed.iter() {
-        let manager = set.p1();
-        let chunk = manager.get_chunk(*chunk_entity);
-        let data = manager.get_chunk_surrounded(chunk.index);
-        queue.0.push_back(ChunksToGenerateQueueElement {
-            index: chunk.index,
-            input_data: data,
-        });
+    for chunk in changed_q.iter() {
+        queue.push(chunk.index, RemeshPriority::Background);
     }
 }
 
 fn update_mesh(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<StandardMaterial>>,
     //    mut ground_materials: ResMut<Assets<GroundMaterial>>,
-    mut ground2_materials: ResMut<Assets<ExtendedMaterial<StandardMaterial, GroundMaterial>>>,
+    ground_material: Res<GroundMaterialHandle>,
     mut mesh_chunk_r: EventReader<ChunkMeshGenerated>,
-    chunks_manager: ChunksManager,
-    terrain_q: Query<(Entity, &Mesh3d, &ChunkMesh)>,
+    chunks: ChunksReader,
+    pending: PendingChunkMeshes,
+    mut terrain_q: Query<(Entity, &Mesh3d, &mut ChunkMesh)>,
 ) {
+    if mesh_chunk_r.is_empty() {
+        return;
+    }
     for ev in mesh_chunk_r.read() {
-        let scale = VOXEL_SCALE;
-        let mesh = ev.mesh.clone().scaled_by(Vec3::splat(scale));
-        let collider = Collider::trimesh_from_mesh(&mesh).unwrap();
+        let existing = terrain_q
+            .iter_mut()
+            .find(|(_, _, chunk)| chunk.index == ev.index);
+        let Some(mesh) = ev.mesh.clone() else {
+            // The chunk no longer has a surface
+            if let Some((entity, _, _)) = existing {
+                commands.entity(entity).despawn_recursive();
+            }
+            // Sent through the commands so it is only seen once the mesh is gone. Chunks with a
+            // mesh are reported by the colliders, once they have the collider they need
+            commands.send_event(ChunkMeshReady {
+                index: ev.index,
+                generation: ev.generation,
+            });
+            continue;
+        };
 
         /* let _ground_handle = ground_materials.add(GroundMaterial {
             alpha_mode: AlphaMode::Blend,
         }); */
-        let _material_handle = materials.add(StandardMaterial {
-            base_color: Color::srgb(0.34, 0.2, 0.2),
-            perceptual_roughness: 1.,
-            ..default()
-        });
-        let _ground2_handle = ground2_materials.add(ExtendedMaterial {
-            base: StandardMaterial {
-                base_color: Color::WHITE,
-                opaque_render_method: OpaqueRendererMethod::Auto,
-                ..Default::default()
-            },
-            extension: GroundMaterial {},
-        });
-
-        if let Some((entity, mesh_handle, _)) = terrain_q
-            .iter()
-            .find(|(_, _, chunk)| chunk.index == ev.index)
-        {
+        if let Some((_, mesh_handle, mut chunk)) = existing {
             meshes.insert(mesh_handle, mesh);
-            commands.entity(entity).insert(collider);
+            chunk.generation = ev.generation;
         } else {
-            let size = chunks_manager.get_amount();
-            let mut offset = (size.as_vec3() * CHUNK_WIDTH as f32 * VOXEL_SCALE) / 2.;
+            let size = chunks.get_amount();
+            let chunk_width = chunks.chunk_width();
+            let mut offset = (size.as_vec3() * chunk_width as f32 * VOXEL_SCALE) / 2.;
             offset.y *= 2.;
             commands
                 .spawn((
                     Transform::from_translation(
-                        (ev.index * CHUNK_WIDTH as u32).as_vec3() * VOXEL_SCALE - offset,
+                        (ev.index * chunk_width as u32).as_vec3() * VOXEL_SCALE - offset,
                     ),
                     Mesh3d(meshes.add(mesh)),
-                    MeshMaterial3d(_ground2_handle),
-                    ChunkMesh { index: ev.index },
+                    MeshMaterial3d(ground_material.0.clone()),
+                    ChunkMesh {
+                        index: ev.index,
+                        generation: ev.generation,
+                        ready_generation: None,
+                    },
                     RigidBody::Static,
-                    collider,
                 ))
                 .observe(
                     |trigger: Trigger<Pointer<Move>>,
                      q_windows: Query<&Window, With<PrimaryWindow>>,
-                     mut commands: Commands| {
-                        if !q_windows.single().cursor_options.visible {
-                            commands.remove_resource::<PointerPosition>();
+                     mut pointers_q: Query<(&InputSource, &mut VoxelPointer)>| {
+                        // A hidden cursor is locked by a camera, which points on its own
+                        if !q_windows
+                            .get_single()
+                            .is_ok_and(|window| window.cursor_options.visible)
+                        {
                             return;
                         }
-                        let Some(pos) = trigger.hit.position else {
-                            return;
-                        };
-                        commands.insert_resource(PointerPosition(pos))
+                        for (source, mut pointer) in pointers_q.iter_mut() {
+                            if *source == InputSource::KeyboardMouse {
+                                pointer.position = trigger.hit.position;
+                            }
+                        }
                     },
                 )
-                .observe(|_: Trigger<Pointer<Out>>, mut commands: Commands| {
-                    commands.remove_resource::<PointerPosition>();
-                });
+                .observe(
+                    |_: Trigger<Pointer<Out>>,
+                     q_windows: Query<&Window, With<PrimaryWindow>>,
+                     mut pointers_q: Query<(&InputSource, &mut VoxelPointer)>| {
+                        if !q_windows
+                            .get_single()
+                            .is_ok_and(|window| window.cursor_options.visible)
+                        {
+                            return;
+                        }
+                        for (source, mut pointer) in pointers_q.iter_mut() {
+                            if *source == InputSource::KeyboardMouse {
+                                pointer.position = None;
+                            }
+                        }
+                    },
+                );
         }
     }
+    if pending.count() == 0 {
+        commands.send_event(FinishedGenerating);
+    }
+}
+
+fn track_edits(
+    mut ready_r: EventReader<ChunkMeshReady>,
+    mut edits: ResMut<PendingEdits>,
+    chunks: ChunksReader,
+    mut applied_w: EventWriter<EditApplied>,
+) {
+    for ready in ready_r.read() {
+        edits.chunk_ready(ready.index, ready.generation);
+    }
+    edits.forget_missing(|index| chunks.chunk_entity(index).is_some());
+    applied_w.send_batch(edits.take_applied().into_iter().map(EditApplied));
 }
 
 /* #[derive(Asset, TypePath, AsBindGroup, Debug, Clone)]
@@ -163,8 +335,69 @@ impl Material for GroundMaterial {
     }
 } */
 
+const GROUND_ALBEDO_BIT: u32 = 1 << 0;
+const GROUND_NORMAL_BIT: u32 = 1 << 1;
+const GROUND_ROUGHNESS_BIT: u32 = 1 << 2;
+
+/// Ground shading that projects textures along the three world axes, so the terrain needs no
+/// unwrapping. Each texture is an array, layer 0 is used for the grass on top and layer 1 for the
+/// dirt below, images can be turned into arrays with [`Image::reinterpret_stacked_2d_as_array`].
 #[derive(Asset, AsBindGroup, Reflect, Debug, Clone)]
-struct GroundMaterial {}
+#[uniform(100, GroundMaterialUniform)]
+pub struct GroundMaterial {
+    /// How many times the textures repeat per world unit.
+    pub texture_scale: f32,
+    /// How sharply the projections blend into each other, higher values give thinner transitions.
+    pub blend_sharpness: f32,
+    #[texture(101, dimension = "2d_array")]
+    #[sampler(102)]
+    pub albedo_textures: Option<Handle<Image>>,
+    /// Tangent space normal maps.
+    #[texture(103, dimension = "2d_array")]
+    pub normal_textures: Option<Handle<Image>>,
+    /// Perceptual roughness, read from the red channel.
+    #[texture(104, dimension = "2d_array")]
+    pub roughness_textures: Option<Handle<Image>>,
+}
+
+impl Default for GroundMaterial {
+    fn default() -> Self {
+        Self {
+            texture_scale: 0.5,
+            blend_sharpness: 4.,
+            albedo_textures: None,
+            normal_textures: None,
+            roughness_textures: None,
+        }
+    }
+}
+
+#[derive(ShaderType, Clone, Debug)]
+pub struct GroundMaterialUniform {
+    texture_scale: f32,
+    blend_sharpness: f32,
+    flags: u32,
+}
+
+impl AsBindGroupShaderType<GroundMaterialUniform> for GroundMaterial {
+    fn as_bind_group_shader_type(&self, _images: &RenderAssets<GpuImage>) -> GroundMaterialUniform {
+        let mut flags = 0;
+        if self.albedo_textures.is_some() {
+            flags |= GROUND_ALBEDO_BIT;
+        }
+        if self.normal_textures.is_some() {
+            flags |= GROUND_NORMAL_BIT;
+        }
+        if self.roughness_textures.is_some() {
+            flags |= GROUND_ROUGHNESS_BIT;
+        }
+        GroundMaterialUniform {
+            texture_scale: self.texture_scale,
+            blend_sharpness: self.blend_sharpness,
+            flags,
+        }
+    }
+}
 
 impl MaterialExtension for GroundMaterial {
     fn fragment_shader() -> ShaderRef {
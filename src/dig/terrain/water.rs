@@ -0,0 +1,569 @@
+use std::time::Duration;
+
+use bevy::{
+    ecs::system::SystemParam,
+    pbr::NotShadowCaster,
+    prelude::*,
+    tasks::{block_on, futures_lite::future, AsyncComputeTaskPool, Task},
+    utils::{HashMap, HashSet},
+};
+
+use crate::{
+    generation::{
+        build_field_mesh,
+        field::{VoxelField, FIELD_PADDING},
+        ChunkSettings,
+    },
+    voxel::{
+        chunks_manager::{ChunksInfo, ChunksManager, ChunksReader},
+        water::{WaterChunk, WATER_FULL},
+        VoxelChunk,
+    },
+};
+
+use super::{handle_voxel_changes, VoxelsDug};
+
+/// Most simulation ticks run in a single frame, so a slow frame doesn't snowball.
+const MAX_TICKS_PER_FRAME: u32 = 4;
+/// Lowest fill level drawn by the water mesh, thinner films of water aren't visible.
+const VISIBLE_LEVEL: u8 = WATER_FULL / 4;
+
+/// A pocket of water in the ground, flooding the tunnels dug into it.
+#[derive(Debug, Clone, Copy)]
+pub struct Aquifer {
+    /// Center of the pocket, in the voxel space of the world.
+    pub center: IVec3,
+    pub radius: f32,
+}
+
+/// Aquifers carved out of the terrain as its chunks are created.
+/// Empty by default, so the terrain has no water unless aquifers are added.
+#[derive(Resource, Debug, Clone, Default)]
+pub struct Aquifers(pub Vec<Aquifer>);
+
+/// Water falling and spreading into the empty voxels, simulated as a cellular automaton.
+/// Only the water around recent digs, and around the water that moved, is looked at.
+#[derive(Resource)]
+pub struct WaterSimulation {
+    tick: Timer,
+    ticks: u32,
+    /// Positions to look at on the next tick, in the voxel space of the world.
+    active: HashSet<IVec3>,
+}
+
+impl WaterSimulation {
+    pub fn new(ticks_per_second: f32) -> WaterSimulation {
+        WaterSimulation {
+            tick: Timer::new(
+                Duration::from_secs_f32(1. / ticks_per_second),
+                TimerMode::Repeating,
+            ),
+            ticks: 0,
+            active: HashSet::new(),
+        }
+    }
+
+    /// Whether every voxel of water has settled.
+    pub fn is_settled(&self) -> bool {
+        self.active.is_empty()
+    }
+}
+
+impl Default for WaterSimulation {
+    fn default() -> Self {
+        WaterSimulation::new(20.)
+    }
+}
+
+/// How deep an entity is in water, kept up to date by the water simulation.
+#[derive(Component, Reflect, Debug, Default, Clone, Copy)]
+#[reflect(Component)]
+pub struct Submerged {
+    /// Height of the column sampled for water, centered on the entity.
+    pub height: f32,
+    /// Fraction of the column under water, from 0 to 1.
+    pub fraction: f32,
+}
+
+impl Submerged {
+    pub fn new(height: f32) -> Submerged {
+        Submerged {
+            height,
+            fraction: 0.,
+        }
+    }
+}
+
+/// The transparent surface of the water held by a chunk.
+#[derive(Component)]
+pub struct WaterMesh;
+
+/// Water meshes are rebuilt at a fixed rate, since flowing water changes every tick.
+#[derive(Resource)]
+struct WaterMeshes {
+    rebuild: Timer,
+    dirty: HashSet<UVec3>,
+    tasks: HashMap<UVec3, Task<Option<Mesh>>>,
+    /// The [`WaterMesh`] entity of every chunk holding visible water.
+    entities: HashMap<UVec3, Entity>,
+    material: Handle<StandardMaterial>,
+}
+
+impl Default for WaterMeshes {
+    fn default() -> Self {
+        Self {
+            rebuild: Timer::from_seconds(0.2, TimerMode::Repeating),
+            dirty: HashSet::new(),
+            tasks: HashMap::new(),
+            entities: HashMap::new(),
+            material: Handle::default(),
+        }
+    }
+}
+
+pub struct WaterPlugin;
+impl Plugin for WaterPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Aquifers>()
+            .init_resource::<WaterSimulation>()
+            .init_resource::<WaterMeshes>()
+            .register_type::<Submerged>()
+            .add_systems(Startup, setup_water_material)
+            .add_systems(
+                Update,
+                (
+                    fill_aquifers.before(handle_voxel_changes),
+                    (
+                        wake_dug_water,
+                        simulate_water,
+                        build_water_meshes,
+                        spawn_water_meshes,
+                        update_submerged,
+                    )
+                        .chain(),
+                ),
+            );
+    }
+}
+
+fn setup_water_material(
+    mut water_meshes: ResMut<WaterMeshes>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    water_meshes.material = materials.add(StandardMaterial {
+        base_color: Color::srgba(0.1, 0.35, 0.6, 0.6),
+        perceptual_roughness: 0.1,
+        alpha_mode: AlphaMode::Blend,
+        ..default()
+    });
+}
+
+/// Empties the voxels of the aquifers and fills them with water.
+fn fill_aquifers(
+    aquifers: Res<Aquifers>,
+    settings: Res<ChunkSettings>,
+    mut chunks_q: Query<(&mut VoxelChunk, &mut WaterChunk), Added<WaterChunk>>,
+) {
+    let width = settings.width as i32;
+    for (mut chunk, mut water) in chunks_q.iter_mut() {
+        let first = water.index.as_ivec3() * width;
+        for aquifer in aquifers.0.iter() {
+            let reach = IVec3::splat(aquifer.radius.ceil() as i32);
+            let min = (aquifer.center - reach - first).max(IVec3::ZERO);
+            let max = (aquifer.center + reach - first).min(IVec3::splat(width - 1));
+            for z in min.z..=max.z {
+                for y in min.y..=max.y {
+                    for x in min.x..=max.x {
+                        let local = IVec3::new(x, y, z);
+                        let distance = (first + local).as_vec3().distance(aquifer.center.as_vec3());
+                        if distance > aquifer.radius {
+                            continue;
+                        }
+                        chunk.set_voxel(local.as_uvec3(), false);
+                        water.set_level(local.as_uvec3(), WATER_FULL);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The water around a dig may now be able to flow into it.
+fn wake_dug_water(
+    mut dug_r: EventReader<VoxelsDug>,
+    mut simulation: ResMut<WaterSimulation>,
+    chunks: ChunksReader,
+) {
+    for dug in dug_r.read() {
+        let center = chunks.world_pos_to_voxel_pos(dug.world_pos);
+        let radius = ChunksManager::world_length_to_voxel_length(dug.radius);
+        let inner = (radius - 1.).max(0.);
+        let outer = radius + 2.;
+        let min = (center - outer).floor().as_ivec3();
+        let max = (center + outer).ceil().as_ivec3();
+        for z in min.z..=max.z {
+            for y in min.y..=max.y {
+                for x in min.x..=max.x {
+                    let pos = IVec3::new(x, y, z);
+                    let distance = pos.as_vec3().distance(center);
+                    if distance >= inner && distance <= outer {
+                        simulation.active.insert(pos);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Water levels and the voxels holding them, positioned in the voxel space of the world.
+trait WaterCells {
+    /// Whether the position can hold water, it has to be an empty voxel inside the world.
+    fn is_open(&self, pos: IVec3) -> bool;
+    fn level(&self, pos: IVec3) -> u8;
+    fn set_level(&mut self, pos: IVec3, level: u8);
+}
+
+/// Water levels of every chunk, alongside the voxels that block the water.
+#[derive(SystemParam)]
+struct WaterGrid<'w, 's> {
+    settings: Res<'w, ChunkSettings>,
+    chunks_info: Option<Res<'w, ChunksInfo>>,
+    chunks_q: Query<'w, 's, (&'static VoxelChunk, &'static mut WaterChunk)>,
+}
+
+impl WaterGrid<'_, '_> {
+    /// The chunk entity and local position of a position in the voxel space of the world.
+    fn locate(&self, pos: IVec3) -> Option<(Entity, UVec3)> {
+        let chunk_width = IVec3::splat(self.settings.width as i32);
+        let index = UVec3::try_from(pos.div_euclid(chunk_width)).ok()?;
+        let entity = self.chunks_info.as_ref()?.chunk_entity(index)?;
+        Some((entity, pos.rem_euclid(chunk_width).as_uvec3()))
+    }
+}
+
+impl WaterCells for WaterGrid<'_, '_> {
+    fn is_open(&self, pos: IVec3) -> bool {
+        let Some((entity, local)) = self.locate(pos) else {
+            return false;
+        };
+        self.chunks_q
+            .get(entity)
+            .is_ok_and(|(chunk, _)| !chunk.get_voxel(local))
+    }
+
+    fn level(&self, pos: IVec3) -> u8 {
+        let Some((entity, local)) = self.locate(pos) else {
+            return 0;
+        };
+        self.chunks_q
+            .get(entity)
+            .map_or(0, |(_, water)| water.get_level(local))
+    }
+
+    fn set_level(&mut self, pos: IVec3, level: u8) {
+        let Some((entity, local)) = self.locate(pos) else {
+            return;
+        };
+        if let Ok((_, mut water)) = self.chunks_q.get_mut(entity) {
+            water.set_level(local, level);
+        }
+    }
+}
+
+fn simulate_water(time: Res<Time>, mut simulation: ResMut<WaterSimulation>, mut grid: WaterGrid) {
+    simulation.tick.tick(time.delta());
+    for _ in 0..simulation
+        .tick
+        .times_finished_this_tick()
+        .min(MAX_TICKS_PER_FRAME)
+    {
+        if simulation.is_settled() {
+            return;
+        }
+        step(&mut simulation, &mut grid);
+    }
+}
+
+/// Lets every active voxel of water fall into the voxel below it, and spreads what can't fall
+/// to its lower neighbors.
+fn step(simulation: &mut WaterSimulation, grid: &mut impl WaterCells) {
+    let mut cells: Vec<IVec3> = simulation.active.drain().collect();
+    cells.sort_unstable_by_key(|pos| pos.y);
+    // Alternate the order sides are tried in so water doesn't lean one way
+    simulation.ticks = simulation.ticks.wrapping_add(1);
+    let sides = [IVec3::X, IVec3::Z, IVec3::NEG_X, IVec3::NEG_Z];
+    let first_side = simulation.ticks as usize % sides.len();
+
+    for pos in cells {
+        let level = grid.level(pos);
+        if level == 0 || !grid.is_open(pos) {
+            continue;
+        }
+        let mut remaining = level;
+
+        let below = pos - IVec3::Y;
+        if grid.is_open(below) {
+            let below_level = grid.level(below);
+            let flow = remaining.min(WATER_FULL - below_level);
+            if flow > 0 {
+                grid.set_level(below, below_level + flow);
+                remaining -= flow;
+                simulation.active.insert(below);
+            }
+        }
+
+        let lower: Vec<IVec3> = (0..sides.len())
+            .map(|i| pos + sides[(first_side + i) % sides.len()])
+            .filter(|side| grid.is_open(*side) && grid.level(*side) < remaining.saturating_sub(1))
+            .collect();
+        // Share the difference with every lower neighbor, keeping a share for this voxel
+        let shares = lower.len() as u8 + 1;
+        for side in lower {
+            let side_level = grid.level(side);
+            // Earlier neighbors may have taken enough that this one is no longer lower
+            if side_level >= remaining.saturating_sub(1) {
+                continue;
+            }
+            let flow = ((remaining - side_level) / shares).max(1);
+            grid.set_level(side, side_level + flow);
+            remaining -= flow;
+            simulation.active.insert(side);
+        }
+
+        if remaining == level {
+            continue;
+        }
+        grid.set_level(pos, remaining);
+        // The water that moved keeps flowing, and the water around it may follow
+        simulation.active.insert(pos);
+        simulation.active.insert(pos + IVec3::Y);
+        for side in sides {
+            simulation.active.insert(pos + side);
+        }
+    }
+}
+
+/// Rebuilds the meshes of the chunks whose water, or the voxels holding it, changed since the last
+/// rebuild.
+fn build_water_meshes(
+    time: Res<Time>,
+    settings: Res<ChunkSettings>,
+    mut water_meshes: ResMut<WaterMeshes>,
+    chunks_q: Query<(Ref<VoxelChunk>, Ref<WaterChunk>)>,
+    chunks_reader: ChunksReader,
+) {
+    for (chunk, water) in chunks_q.iter() {
+        if water.is_changed() || (chunk.is_changed() && !water.is_dry()) {
+            water_meshes.dirty.insert(water.index);
+        }
+    }
+    water_meshes.rebuild.tick(time.delta());
+    if !water_meshes.rebuild.just_finished() || water_meshes.dirty.is_empty() {
+        return;
+    }
+
+    let chunk_width = settings.width;
+    let width = chunk_width as i32;
+    let chunks: HashMap<UVec3, (&VoxelChunk, &WaterChunk)> = chunks_q
+        .iter()
+        .map(|(chunk, water)| (chunk.index, (chunk.into_inner(), water.into_inner())))
+        .collect();
+    // Whether the position, in the voxel space of the world, holds visible water
+    let is_wet = |pos: IVec3| {
+        let Ok(index) = UVec3::try_from(pos.div_euclid(IVec3::splat(width))) else {
+            return false;
+        };
+        let local = pos.rem_euclid(IVec3::splat(width)).as_uvec3();
+        chunks.get(&index).is_some_and(|(chunk, water)| {
+            !chunk.get_voxel(local) && water.get_level(local) >= VISIBLE_LEVEL
+        })
+    };
+
+    let padded = chunk_width + FIELD_PADDING * 2;
+    for index in std::mem::take(&mut water_meshes.dirty) {
+        let first = index.as_ivec3() * width;
+        let mut voxels = vec![false; padded * padded * padded];
+        for (i, voxel) in voxels.iter_mut().enumerate() {
+            let pos = IVec3::new(
+                (i % padded) as i32,
+                (i / padded % padded) as i32,
+                (i / (padded * padded)) as i32,
+            );
+            *voxel = is_wet(first + pos - IVec3::splat(FIELD_PADDING as i32));
+        }
+        let field = VoxelField::new(voxels, chunk_width);
+        // The mesh space starts one voxel before the chunk
+        let translation = chunks_reader.voxel_pos_to_world_pos(first.as_vec3() - Vec3::ONE);
+        // Dropping the task of an outdated mesh cancels it
+        water_meshes.tasks.insert(
+            index,
+            AsyncComputeTaskPool::get().spawn(async move { build_field_mesh(&field, translation) }),
+        );
+    }
+}
+
+fn spawn_water_meshes(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut water_meshes: ResMut<WaterMeshes>,
+    chunks: ChunksReader,
+    water_mesh_q: Query<&Mesh3d, With<WaterMesh>>,
+) {
+    let chunk_width = chunks.chunk_width() as u32;
+    let WaterMeshes {
+        tasks,
+        entities,
+        material,
+        ..
+    } = &mut *water_meshes;
+    tasks.retain(|index, task| {
+        let Some(mesh) = block_on(future::poll_once(task)) else {
+            return true;
+        };
+        let existing = entities.get(index).copied();
+        match (mesh, existing) {
+            (Some(mesh), Some(entity)) => {
+                if let Ok(mesh_handle) = water_mesh_q.get(entity) {
+                    meshes.insert(mesh_handle, mesh);
+                }
+            }
+            (Some(mesh), None) => {
+                // The mesh space starts one voxel before the chunk
+                let origin = (*index * chunk_width).as_vec3() - Vec3::ONE;
+                let entity = commands
+                    .spawn((
+                        Transform::from_translation(chunks.voxel_pos_to_world_pos(origin)),
+                        Mesh3d(meshes.add(mesh)),
+                        MeshMaterial3d(material.clone()),
+                        WaterMesh,
+                        NotShadowCaster,
+                        PickingBehavior::IGNORE,
+                    ))
+                    .id();
+                entities.insert(*index, entity);
+            }
+            (None, Some(entity)) => {
+                commands.entity(entity).despawn();
+                entities.remove(index);
+            }
+            (None, None) => {}
+        }
+        false
+    });
+}
+
+fn update_submerged(
+    chunks: ChunksReader,
+    water_q: Query<&WaterChunk>,
+    mut submerged_q: Query<(&mut Submerged, &GlobalTransform)>,
+) {
+    let width = IVec3::splat(chunks.chunk_width() as i32);
+    let level = |pos: IVec3| {
+        let Ok(index) = UVec3::try_from(pos.div_euclid(width)) else {
+            return 0;
+        };
+        chunks
+            .chunk_entity(index)
+            .and_then(|entity| water_q.get(entity).ok())
+            .map_or(0, |water| water.get_level(pos.rem_euclid(width).as_uvec3()))
+    };
+    for (mut submerged, transform) in submerged_q.iter_mut() {
+        let center = chunks.world_pos_to_voxel_pos(transform.translation());
+        let height = ChunksManager::world_length_to_voxel_length(submerged.height);
+        // Sample the column once per voxel it spans
+        let samples = height.ceil().max(1.) as usize;
+        let filled: f32 = (0..samples)
+            .map(|i| {
+                let y = center.y - height * 0.5 + (i as f32 + 0.5) * height / samples as f32;
+                level(Vec3::new(center.x, y, center.z).round().as_ivec3()) as f32
+            })
+            .sum();
+        let fraction = filled / (samples as f32 * WATER_FULL as f32);
+        if submerged.fraction != fraction {
+            submerged.fraction = fraction;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::{
+        prelude::*,
+        utils::{HashMap, HashSet},
+    };
+
+    use super::{step, WaterCells, WaterSimulation};
+    use crate::voxel::water::WATER_FULL;
+
+    /// Open voxels with their water, every other voxel being solid.
+    #[derive(Default)]
+    struct TestCells {
+        open: HashSet<IVec3>,
+        levels: HashMap<IVec3, u8>,
+    }
+
+    impl TestCells {
+        fn total(&self) -> u32 {
+            self.levels.values().map(|level| *level as u32).sum()
+        }
+    }
+
+    impl WaterCells for TestCells {
+        fn is_open(&self, pos: IVec3) -> bool {
+            self.open.contains(&pos)
+        }
+
+        fn level(&self, pos: IVec3) -> u8 {
+            self.levels.get(&pos).copied().unwrap_or(0)
+        }
+
+        fn set_level(&mut self, pos: IVec3, level: u8) {
+            self.levels.insert(pos, level);
+        }
+    }
+
+    /// Steps the water until it settles, checking that none is created or lost on the way.
+    fn settle(cells: &mut TestCells) {
+        let mut simulation = WaterSimulation::default();
+        simulation.active.extend(cells.levels.keys().copied());
+        let total = cells.total();
+        for _ in 0..10_000 {
+            if simulation.is_settled() {
+                return;
+            }
+            step(&mut simulation, cells);
+            assert_eq!(cells.total(), total);
+        }
+        panic!("the water never settled");
+    }
+
+    #[test]
+    fn water_is_conserved_as_it_falls_and_spreads() {
+        let mut cells = TestCells::default();
+        for z in 0..5 {
+            for y in 0..3 {
+                for x in 0..5 {
+                    cells.open.insert(IVec3::new(x, y, z));
+                }
+            }
+        }
+        cells.levels.insert(IVec3::new(2, 2, 2), WATER_FULL);
+        cells.levels.insert(IVec3::new(2, 1, 2), WATER_FULL);
+        settle(&mut cells);
+        assert!(cells
+            .levels
+            .iter()
+            .all(|(pos, level)| pos.y == 0 || *level == 0));
+    }
+
+    #[test]
+    fn full_voxels_next_to_each_other_stay_full() {
+        let mut cells = TestCells::default();
+        for x in 0..3 {
+            cells.open.insert(IVec3::new(x, 0, 0));
+            cells.levels.insert(IVec3::new(x, 0, 0), WATER_FULL);
+        }
+        settle(&mut cells);
+        assert!(cells.levels.values().all(|level| *level == WATER_FULL));
+    }
+}
@@ -0,0 +1,130 @@
+use bevy::prelude::*;
+
+/// How far around a vertex voxels are looked at to compute its ambient occlusion.
+pub const OCCLUSION_RADIUS: i32 = 2;
+/// How many voxels of neighbor data surround a chunk when sampling the field around its mesh.
+/// The mesh itself spans the chunk plus one voxel on each side, and the ambient occlusion looks
+/// further than that.
+pub const FIELD_PADDING: usize = 1 + OCCLUSION_RADIUS as usize;
+
+/// Voxel occupancy of a chunk and its neighbors, in the coordinate space of the generated mesh.
+#[derive(Debug, Clone)]
+pub struct VoxelField {
+    width: usize,
+    voxels: Vec<bool>,
+}
+
+impl VoxelField {
+    pub fn new(voxels: Vec<bool>, chunk_width: usize) -> VoxelField {
+        let width = chunk_width + FIELD_PADDING * 2;
+        assert_eq!(voxels.len(), width * width * width);
+        VoxelField { width, voxels }
+    }
+
+    /// Builds a field holding the given solid voxels, positioned relatively to the first voxel of
+    /// a chunk that doesn't exist, for voxels that have been taken out of the world.
+    /// `chunk_width` doesn't have to match the chunks of the world, only to fit every position.
+    pub fn from_positions(positions: &[IVec3], chunk_width: usize) -> VoxelField {
+        let width = chunk_width + FIELD_PADDING * 2;
+        let mut voxels = vec![false; width * width * width];
+        let stride = width as i32;
+        for pos in positions {
+            let pos = *pos + IVec3::splat(FIELD_PADDING as i32);
+            voxels[(pos.x + pos.y * stride + pos.z * stride * stride) as usize] = true;
+        }
+        VoxelField::new(voxels, chunk_width)
+    }
+
+    /// Amount of voxels along each axis of the mesh space, the chunk plus one voxel on each side.
+    pub fn mesh_width(&self) -> i32 {
+        (self.width - FIELD_PADDING * 2 + 2) as i32
+    }
+
+    /// Whether the voxel at the given mesh space position is solid.
+    /// Positions outside of the sampled area are considered empty.
+    pub fn is_solid(&self, pos: IVec3) -> bool {
+        // Mesh vertices are generated from data padded by a single voxel
+        let pos = pos + IVec3::splat(FIELD_PADDING as i32 - 1);
+        let width = self.width as i32;
+        if pos.cmplt(IVec3::ZERO).any() || pos.cmpge(IVec3::splat(width)).any() {
+            return false;
+        }
+        self.voxels[(pos.x + pos.y * width + pos.z * width * width) as usize]
+    }
+
+    fn density(&self, pos: IVec3) -> f32 {
+        if self.is_solid(pos) {
+            1.
+        } else {
+            0.
+        }
+    }
+
+    /// Central difference gradient of the voxel density at a grid point.
+    fn grid_gradient(&self, pos: IVec3) -> Vec3 {
+        Vec3::new(
+            self.density(pos + IVec3::X) - self.density(pos - IVec3::X),
+            self.density(pos + IVec3::Y) - self.density(pos - IVec3::Y),
+            self.density(pos + IVec3::Z) - self.density(pos - IVec3::Z),
+        ) * 0.5
+    }
+
+    /// Trilinearly interpolated density gradient at a mesh space position.
+    pub fn gradient(&self, pos: Vec3) -> Vec3 {
+        let base = pos.floor();
+        let t = pos - base;
+        let base = base.as_ivec3();
+        let mut gradient = Vec3::ZERO;
+        for corner in 0..8 {
+            let offset = IVec3::new(corner & 1, (corner >> 1) & 1, (corner >> 2) & 1);
+            let weight = Vec3::select(offset.cmpeq(IVec3::ONE), t, Vec3::ONE - t);
+            gradient += self.grid_gradient(base + offset) * weight.x * weight.y * weight.z;
+        }
+        gradient
+    }
+
+    /// Surface normal at a mesh space position, pointing from solid voxels towards empty ones.
+    /// Since it only depends on the voxel data, both sides of a chunk border get the same normal.
+    pub fn normal(&self, pos: Vec3) -> Vec3 {
+        let normal = -self.gradient(pos);
+        if normal.length_squared() > f32::EPSILON {
+            return normal.normalize();
+        }
+        // The smoothed gradient cancels out on thin features, fall back to the edge the vertex lies on
+        let a = pos.floor().as_ivec3();
+        let b = pos.ceil().as_ivec3();
+        let edge = (b - a).as_vec3() * (self.density(a) - self.density(b));
+        edge.try_normalize().unwrap_or(Vec3::Y)
+    }
+
+    /// Ambient light reaching a mesh space position, from 0 (fully occluded) to 1 (fully open).
+    /// Only the voxels in the hemisphere the normal faces can occlude the vertex, closer ones
+    /// weighing more.
+    pub fn ambient_occlusion(&self, pos: Vec3, normal: Vec3) -> f32 {
+        let center = pos.round().as_ivec3();
+        let max_distance_squared = (OCCLUSION_RADIUS as f32 + 0.5).powi(2);
+        let mut total = 0.;
+        let mut occluded = 0.;
+        for z in -OCCLUSION_RADIUS..=OCCLUSION_RADIUS {
+            for y in -OCCLUSION_RADIUS..=OCCLUSION_RADIUS {
+                for x in -OCCLUSION_RADIUS..=OCCLUSION_RADIUS {
+                    let sample = center + IVec3::new(x, y, z);
+                    let direction = sample.as_vec3() - pos;
+                    let distance_squared = direction.length_squared();
+                    if direction.dot(normal) <= 0. || distance_squared > max_distance_squared {
+                        continue;
+                    }
+                    let weight = 1. / (1. + distance_squared);
+                    total += weight;
+                    if self.is_solid(sample) {
+                        occluded += weight;
+                    }
+                }
+            }
+        }
+        if total == 0. {
+            return 1.;
+        }
+        1. - occluded / total
+    }
+}
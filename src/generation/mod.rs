@@ -1,4 +1,13 @@
+use std::{
+    num::NonZeroU64,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
 use bevy::{
+    ecs::system::SystemParam,
     prelude::*,
     render::{
         extract_resource::*,
@@ -6,46 +15,124 @@ use bevy::{
         mesh::Indices,
         render_asset::*,
         render_graph::*,
-        render_resource::{binding_types::storage_buffer, *},
+        render_resource::{binding_types::storage_buffer_sized, *},
         renderer::*,
         storage::*,
         *,
     },
+    tasks::{block_on, futures_lite::future, AsyncComputeTaskPool, Task},
     utils::hashbrown::HashMap,
 };
 
-use crate::dig::terrain::{ChunksToGenerateQueue, FinishedGenerating};
+use crate::{
+    dig::terrain::{ChunksToGenerateQueue, VOXEL_SCALE},
+    voxel::chunks_manager::ChunksReader,
+};
+use field::{VoxelField, FIELD_PADDING};
+
+pub mod field;
+mod marching_cubes;
 
 const SHADER_ASSET_PATH: &str = "shaders/marching_cubes.wgsl";
 
-pub const CHUNK_WIDTH: usize = 31;
-pub const CHUNK_DATA: usize = CHUNK_WIDTH * CHUNK_WIDTH * CHUNK_WIDTH;
-pub const INPUT_CHUNK_WIDTH: usize = CHUNK_WIDTH + 2;
-pub const BUFFER_LEN_UNCOMPRESSED: usize =
-    INPUT_CHUNK_WIDTH * INPUT_CHUNK_WIDTH * INPUT_CHUNK_WIDTH;
-pub const BUFFER_LEN: usize = BUFFER_LEN_UNCOMPRESSED / 32;
+pub const DEFAULT_CHUNK_WIDTH: usize = 31;
 const MAX_VERTICES_PER_CUBE: usize = 12;
-const TRI_BUFFER_LEN: usize =
-    (CHUNK_WIDTH + 2) * (CHUNK_WIDTH + 2) * (CHUNK_WIDTH + 2) * MAX_VERTICES_PER_CUBE;
-const DISPATCH: u32 = (CHUNK_WIDTH as u32 + 1) / 4;
+const WORKGROUP_SIZE: u32 = 4;
+
+/// Size of the voxel chunks, shared by the voxel data, the meshing and the compute shader.
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct ChunkSettings {
+    /// How many voxels a chunk holds along each axis.
+    pub width: usize,
+}
+
+impl Default for ChunkSettings {
+    fn default() -> Self {
+        ChunkSettings::new(DEFAULT_CHUNK_WIDTH)
+    }
+}
+
+impl ChunkSettings {
+    pub fn new(width: usize) -> ChunkSettings {
+        if width < 2 {
+            panic!("Chunk width should be atleast 2");
+        }
+        ChunkSettings { width }
+    }
+
+    /// Amount of voxels in a chunk.
+    pub fn data_len(&self) -> usize {
+        self.width * self.width * self.width
+    }
+
+    /// Width of the compute shader input, the chunk surrounded by one voxel of its neighbors.
+    pub fn input_width(&self) -> usize {
+        self.width + 2
+    }
+
+    /// Amount of voxels in the compute shader input.
+    pub fn input_len(&self) -> usize {
+        self.input_width() * self.input_width() * self.input_width()
+    }
+
+    /// Amount of `u32` in the compute shader input once compressed to bits.
+    pub fn buffer_len(&self) -> usize {
+        self.input_len().div_ceil(32)
+    }
+
+    /// Amount of vertices in the compute shader output.
+    pub fn tri_buffer_len(&self) -> usize {
+        self.input_len() * MAX_VERTICES_PER_CUBE
+    }
+
+    /// Workgroups to dispatch along each axis to cover every cube of the input.
+    pub fn dispatch(&self) -> u32 {
+        (self.input_width() as u32 - 1).div_ceil(WORKGROUP_SIZE)
+    }
+
+    fn shader_defs(&self) -> Vec<ShaderDefVal> {
+        vec![
+            ShaderDefVal::UInt("CHUNK_WIDTH".into(), self.width as u32),
+            ShaderDefVal::UInt("MAX_VERTICES_PER_CUBE".into(), MAX_VERTICES_PER_CUBE as u32),
+            ShaderDefVal::UInt("WORKGROUP_SIZE".into(), WORKGROUP_SIZE),
+        ]
+    }
+}
+
+/// The chunks closest to the first entity with this component are generated first.
+#[derive(Component, Debug, Default)]
+pub struct GenerationFocus;
 
 pub(crate) struct GpuReadbackPlugin;
 impl Plugin for GpuReadbackPlugin {
     fn build(&self, app: &mut App) {
         app.add_plugins((ExtractResourcePlugin::<ReadbackBuffer>::default(),))
             .add_event::<ChunkMeshGenerated>()
+            .init_resource::<ChunkMeshTasks>()
+            .init_resource::<ComputePipelineReady>()
+            .insert_resource(ChunkMeshBudget(2))
             .add_systems(Startup, setup)
+            .add_systems(Update, poll_mesh_tasks)
             .add_systems(PostUpdate, handle_queue);
     }
 
     fn finish(&self, app: &mut App) {
+        let settings = *app.world().resource::<ChunkSettings>();
+        let pipeline_ready = app.world().resource::<ComputePipelineReady>().clone();
         let render_app = app.sub_app_mut(RenderApp);
-        render_app.init_resource::<ComputePipeline>().add_systems(
-            Render,
-            prepare_bind_group
-                .in_set(RenderSet::PrepareBindGroups)
-                .run_if(resource_exists::<BuildTerrain>),
-        );
+        render_app
+            .insert_resource(settings)
+            .insert_resource(pipeline_ready)
+            .init_resource::<ComputePipeline>()
+            .add_systems(
+                Render,
+                (
+                    prepare_bind_group
+                        .in_set(RenderSet::PrepareBindGroups)
+                        .run_if(resource_exists::<BuildTerrain>),
+                    check_pipeline_ready.in_set(RenderSet::Prepare),
+                ),
+            );
 
         render_app
             .add_systems(ExtractSchedule, extract_build_terrain)
@@ -56,28 +143,113 @@ impl Plugin for GpuReadbackPlugin {
     }
 }
 
+/// Whether the marching cubes compute pipeline has finished compiling.
+/// Shared between the main and render worlds, since only the latter knows about the pipeline.
+#[derive(Resource, Clone, Default)]
+pub struct ComputePipelineReady(Arc<AtomicBool>);
+
+impl ComputePipelineReady {
+    pub fn is_ready(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+fn check_pipeline_ready(
+    pipeline_cache: Res<PipelineCache>,
+    pipeline: Res<ComputePipeline>,
+    ready: Res<ComputePipelineReady>,
+) {
+    if let CachedPipelineState::Ok(_) = pipeline_cache.get_compute_pipeline_state(pipeline.pipeline)
+    {
+        ready.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Counts the chunks whose mesh is queued, being generated or waiting to be applied.
+#[derive(SystemParam)]
+pub struct PendingChunkMeshes<'w, 's> {
+    queue: Res<'w, ChunksToGenerateQueue>,
+    readback_q: Query<'w, 's, &'static ReadBackIndex>,
+    tasks: Res<'w, ChunkMeshTasks>,
+}
+
+impl PendingChunkMeshes<'_, '_> {
+    pub fn count(&self) -> usize {
+        self.queue.len()
+            + self.readback_q.iter().len()
+            + self.tasks.tasks.len()
+            + self.tasks.ready.len()
+    }
+}
+
+/// A finished chunk mesh, already scaled to world units.
+/// `None` when the chunk has no surface, for example once it has been dug out entirely.
 #[derive(Event)]
 pub struct ChunkMeshGenerated {
     pub index: UVec3,
-    pub mesh: Mesh,
+    /// The [`VoxelChunk::generation`](crate::voxel::VoxelChunk::generation) the mesh was built from.
+    pub generation: u64,
+    pub mesh: Option<Mesh>,
 }
 
 impl ChunkMeshGenerated {
-    pub fn new(index: UVec3, mesh: Mesh) -> ChunkMeshGenerated {
-        ChunkMeshGenerated { index, mesh }
+    pub fn new(index: UVec3, generation: u64, mesh: Option<Mesh>) -> ChunkMeshGenerated {
+        ChunkMeshGenerated {
+            index,
+            generation,
+            mesh,
+        }
     }
 }
 
+/// How many finished chunk meshes are handed over to the world each frame.
+#[derive(Resource)]
+pub struct ChunkMeshBudget(pub usize);
+
+/// Meshes being built in the background from readback data, at most one per chunk.
+#[derive(Resource, Default)]
+struct ChunkMeshTasks {
+    tasks: HashMap<UVec3, Task<ChunkMeshGenerated>>,
+    ready: Vec<ChunkMeshGenerated>,
+}
+
+fn poll_mesh_tasks(
+    mut tasks: ResMut<ChunkMeshTasks>,
+    budget: Res<ChunkMeshBudget>,
+    mut chunk_mesh_w: EventWriter<ChunkMeshGenerated>,
+) {
+    let tasks = &mut *tasks;
+    tasks.tasks.retain(|index, task| {
+        let Some(generated) = block_on(future::poll_once(task)) else {
+            return true;
+        };
+        // A newer mesh for the same chunk replaces one that is still waiting
+        tasks.ready.retain(|ready| ready.index != *index);
+        tasks.ready.push(generated);
+        false
+    });
+    let count = budget.0.min(tasks.ready.len());
+    chunk_mesh_w.send_batch(tasks.ready.drain(..count));
+}
+
 #[derive(Component)]
 pub struct ReadBackIndex(UVec3);
 
+/// Snapshot of the voxels the readback's mesh is generated from.
+#[derive(Component)]
+struct ReadBackField {
+    field: VoxelField,
+    generation: u64,
+}
+
 fn handle_queue(
     mut commands: Commands,
     mut buffers: ResMut<Assets<ShaderStorageBuffer>>,
     mut queue: ResMut<ChunksToGenerateQueue>,
     readback_q: Query<&ReadBackIndex>,
-    mut finished_w: EventWriter<FinishedGenerating>,
     maybe_buffer: Option<ResMut<ReadbackBuffer>>,
+    chunks: ChunksReader,
+    focus_q: Query<&GlobalTransform, With<GenerationFocus>>,
 ) {
     commands.remove_resource::<BuildTerrain>();
 
@@ -87,20 +259,42 @@ fn handle_queue(
     if readback_q.iter().len() > 0 {
         return;
     }
-    let Some(element) = queue.0.pop_front() else {
+    let chunk_width = chunks.chunk_width() as f32;
+    let focus = focus_q
+        .iter()
+        .next()
+        .map(|transform| chunks.world_pos_to_voxel_pos(transform.translation()) / chunk_width);
+    let Some(index) = queue.pop(focus) else {
         return;
     };
-    if queue.0.is_empty() {
-        finished_w.send(FinishedGenerating);
-    }
-    let compressed = compress_bools_to_u32(&element.input_data);
+    let Some(generation) = chunks
+        .get_chunk_by_index(index)
+        .map(|chunk| chunk.generation())
+    else {
+        return;
+    };
+    // The voxels are read now rather than when queued so the mesh reflects the latest edits
+    let input_data = chunks.get_chunk_surrounded(index);
+    let field = VoxelField::new(
+        chunks.get_chunk_padded(index, FIELD_PADDING),
+        chunks.chunk_width(),
+    );
+    let compressed = compress_bools_to_u32(&input_data);
     let mut input_buffer = ShaderStorageBuffer::from(compressed);
     input_buffer.buffer_description.usage |= BufferUsages::COPY_SRC;
     let handle = buffers.add(input_buffer);
     commands.insert_resource::<BuildTerrain>(BuildTerrain);
     buffer.input = handle;
-    buffers.insert(&buffer.output, make_empty_triangles_buffer());
-    spawn_readback(&mut commands, buffer.output.clone(), element.index);
+    buffers.insert(
+        &buffer.output,
+        make_empty_triangles_buffer(chunks.settings()),
+    );
+    spawn_readback(
+        &mut commands,
+        buffer.output.clone(),
+        index,
+        ReadBackField { field, generation },
+    );
 }
 
 fn compress_bools_to_u32(bools: &[bool]) -> Vec<u32> {
@@ -132,44 +326,133 @@ fn spawn_readback(
     commands: &mut Commands,
     buffer_handle: Handle<ShaderStorageBuffer>,
     index: UVec3,
+    field: ReadBackField,
 ) {
     commands
-        .spawn((Readback::buffer(buffer_handle), ReadBackIndex(index)))
+        .spawn((Readback::buffer(buffer_handle), ReadBackIndex(index), field))
         .observe(
             |trigger: Trigger<ReadbackComplete>,
              mut commanads: Commands,
-             mut chunk_mesh_w: EventWriter<ChunkMeshGenerated>,
-             index_q: Query<&ReadBackIndex>| {
-                let index = index_q.get(trigger.entity()).unwrap().0;
+             mut tasks: ResMut<ChunkMeshTasks>,
+             settings: Res<ChunkSettings>,
+             chunks: ChunksReader,
+             index_q: Query<(&ReadBackIndex, &ReadBackField)>| {
+                let (index, field) = index_q.get(trigger.entity()).unwrap();
+                let index = index.0;
                 let readback: Vec<Vec4> = trigger.event().to_shader_type();
                 if readback[0].w == -1. {
                     return;
                 }
                 commanads.entity(trigger.entity()).despawn();
-                let filtered: Vec<Vec3> = readback
-                    .iter()
-                    .filter(|v| v.w == 0.0)
-                    .map(|v4| v4.xyz())
-                    .collect();
-                let (indices, unique) = deduplicate_vertices(&filtered, 0.1);
-                println!("Readback {:?}", indices.len());
-                if indices.len() > 0 {
-                    let mesh = create_terrain_mesh(&indices, &unique);
-                    chunk_mesh_w.send(ChunkMeshGenerated::new(index, mesh));
-                }
+                let generation = field.generation;
+                let field = field.field.clone();
+                // The mesh space starts one voxel before the chunk
+                let translation = chunks
+                    .voxel_pos_to_world_pos((index * settings.width as u32).as_vec3() - Vec3::ONE);
+                let task = AsyncComputeTaskPool::get().spawn(async move {
+                    let mesh = build_chunk_mesh(&readback, &field, translation);
+                    ChunkMeshGenerated::new(index, generation, mesh)
+                });
+                // Dropping the task of an outdated mesh cancels it
+                tasks.tasks.insert(index, task);
             },
         );
 }
 
-pub fn create_terrain_mesh(indices: &Vec<usize>, uniques: &Vec<Vec3>) -> Mesh {
+/// Turns the raw compute shader output into a world scale mesh.
+/// This is expensive, so it runs on the [`AsyncComputeTaskPool`].
+fn build_chunk_mesh(readback: &[Vec4], field: &VoxelField, translation: Vec3) -> Option<Mesh> {
+    let filtered: Vec<Vec3> = readback
+        .iter()
+        .filter(|v| v.w == 0.0)
+        .map(|v4| v4.xyz())
+        .collect();
+    mesh_from_vertices(&filtered, field, translation)
+}
+
+/// Meshes voxels that aren't part of a chunk, like detached islands, on the CPU.
+/// `translation` is where the mesh is placed in the world, which its uvs are computed from.
+pub fn build_field_mesh(field: &VoxelField, translation: Vec3) -> Option<Mesh> {
+    let vertices = marching_cubes::march(field, field.mesh_width());
+    mesh_from_vertices(&vertices, field, translation)
+}
+
+/// Turns a triangle soup in the mesh space of `field` into a world scale mesh.
+fn mesh_from_vertices(vertices: &[Vec3], field: &VoxelField, translation: Vec3) -> Option<Mesh> {
+    let (indices, unique) = deduplicate_vertices(vertices, 0.1);
+    if indices.is_empty() {
+        return None;
+    }
+    Some(
+        create_terrain_mesh(&indices, &unique, field, translation)
+            .scaled_by(Vec3::splat(VOXEL_SCALE)),
+    )
+}
+
+/// Builds the mesh of a chunk in the mesh space of `field`, to be scaled to world units and placed
+/// at `translation`.
+pub fn create_terrain_mesh(
+    indices: &Vec<usize>,
+    uniques: &Vec<Vec3>,
+    field: &VoxelField,
+    translation: Vec3,
+) -> Mesh {
     let indices_u32: Vec<u32> = indices.iter().map(|i| *i as u32).collect();
+    // Normals come from the voxel field rather than the triangles so they match across chunk borders
+    let normals: Vec<Vec3> = uniques.iter().map(|pos| field.normal(*pos)).collect();
+    // Uvs are in world units and use the world position, so textures tile seamlessly between chunks
+    let (uvs, tangents): (Vec<Vec2>, Vec<Vec4>) = uniques
+        .iter()
+        .zip(normals.iter())
+        .map(|(pos, normal)| triplanar_uv_and_tangent(translation + *pos * VOXEL_SCALE, *normal))
+        .unzip();
+    // Ambient occlusion is baked into the vertex colors, which the ground shader applies
+    let colors: Vec<[f32; 4]> = uniques
+        .iter()
+        .zip(normals.iter())
+        .map(|(pos, normal)| {
+            let occlusion = field.ambient_occlusion(*pos, *normal);
+            [occlusion, occlusion, occlusion, 1.]
+        })
+        .collect();
     Mesh::new(
         PrimitiveTopology::TriangleList,
         RenderAssetUsages::MAIN_WORLD | RenderAssetUsages::RENDER_WORLD,
     )
     .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, uniques.clone())
+    .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, normals)
+    .with_inserted_attribute(Mesh::ATTRIBUTE_UV_0, uvs)
+    .with_inserted_attribute(Mesh::ATTRIBUTE_TANGENT, tangents)
+    .with_inserted_attribute(Mesh::ATTRIBUTE_COLOR, colors)
     .with_inserted_indices(Indices::U32(indices_u32))
-    .with_computed_normals()
+}
+
+/// Projects the world position on the plane facing the dominant axis of the normal, along the same
+/// axes as the projections of the ground shader, and returns the uv with its matching tangent.
+/// These are for materials sampling a single set of uvs, like [`StandardMaterial`]. The ground
+/// shader uses them for the textures of its base material, and blends its texture arrays from
+/// the world position instead.
+/// A mesh has one uv per vertex, so triangles whose vertices face different dominant axes stretch
+/// their texture across the change of projection.
+fn triplanar_uv_and_tangent(pos: Vec3, normal: Vec3) -> (Vec2, Vec4) {
+    let abs = normal.abs();
+    let (uv, u_axis, v_axis) = if abs.x >= abs.y && abs.x >= abs.z {
+        (Vec2::new(pos.z, pos.y), Vec3::Z, Vec3::Y)
+    } else if abs.y >= abs.z {
+        (Vec2::new(pos.x, pos.z), Vec3::X, Vec3::Z)
+    } else {
+        (Vec2::new(pos.x, pos.y), Vec3::X, Vec3::Y)
+    };
+    let tangent = (u_axis - normal * normal.dot(u_axis))
+        .try_normalize()
+        .unwrap_or_else(|| normal.any_orthonormal_vector());
+    // Bitangents are reconstructed as `sign * cross(normal, tangent)`
+    let sign = if normal.cross(tangent).dot(v_axis) < 0. {
+        -1.
+    } else {
+        1.
+    };
+    (uv, tangent.extend(sign))
 }
 
 trait SpatialHash {
@@ -192,7 +475,7 @@ impl SpatialHash for Vec3 {
     }
 }
 
-fn deduplicate_vertices(vec: &Vec<Vec3>, epsilon: f32) -> (Vec<usize>, Vec<Vec3>) {
+fn deduplicate_vertices(vec: &[Vec3], epsilon: f32) -> (Vec<usize>, Vec<Vec3>) {
     let mut unique_pos: Vec<Vec3> = Vec::new();
     let mut indices: Vec<usize> = Vec::new();
     let mut hash_map: HashMap<(i32, i32, i32), Vec<usize>> = HashMap::new();
@@ -241,9 +524,9 @@ impl ReadbackBuffer {
     }
 }
 
-fn make_empty_triangles_buffer() -> ShaderStorageBuffer {
+fn make_empty_triangles_buffer(settings: &ChunkSettings) -> ShaderStorageBuffer {
     let mut output_buffer =
-        ShaderStorageBuffer::from(vec![Vec4::new(0., 0., 0., -1.); TRI_BUFFER_LEN]);
+        ShaderStorageBuffer::from(vec![Vec4::new(0., 0., 0., -1.); settings.tri_buffer_len()]);
     output_buffer.buffer_description.usage |= BufferUsages::COPY_SRC;
     output_buffer
 }
@@ -304,14 +587,25 @@ struct ComputePipeline {
 
 impl FromWorld for ComputePipeline {
     fn from_world(world: &mut World) -> Self {
+        let settings = *world.resource::<ChunkSettings>();
         let render_device = world.resource::<RenderDevice>();
         let layout = render_device.create_bind_group_layout(
             None,
             &BindGroupLayoutEntries::sequential(
                 ShaderStages::COMPUTE,
                 (
-                    storage_buffer::<[u32; BUFFER_LEN]>(false),
-                    storage_buffer::<[Vec4; TRI_BUFFER_LEN]>(false),
+                    storage_buffer_sized(
+                        false,
+                        NonZeroU64::new(
+                            (settings.buffer_len() * std::mem::size_of::<u32>()) as u64,
+                        ),
+                    ),
+                    storage_buffer_sized(
+                        false,
+                        NonZeroU64::new(
+                            (settings.tri_buffer_len() * std::mem::size_of::<Vec4>()) as u64,
+                        ),
+                    ),
                 ),
             ),
         );
@@ -322,7 +616,7 @@ impl FromWorld for ComputePipeline {
             layout: vec![layout.clone()],
             push_constant_ranges: Vec::new(),
             shader: shader.clone(),
-            shader_defs: Vec::new(),
+            shader_defs: settings.shader_defs(),
             entry_point: "main".into(),
             zero_initialize_workgroup_memory: false,
         });
@@ -349,6 +643,7 @@ impl render_graph::Node for ComputeNode {
         let pipeline_cache = world.resource::<PipelineCache>();
         let pipeline = world.resource::<ComputePipeline>();
         let bind_group = world.resource::<GpuBufferBindGroup>();
+        let dispatch = world.resource::<ChunkSettings>().dispatch();
 
         if let Some(init_pipeline) = pipeline_cache.get_compute_pipeline(pipeline.pipeline) {
             println!("Passed");
@@ -362,7 +657,7 @@ impl render_graph::Node for ComputeNode {
 
             pass.set_bind_group(0, &bind_group.0, &[]);
             pass.set_pipeline(init_pipeline);
-            pass.dispatch_workgroups(DISPATCH, DISPATCH, DISPATCH);
+            pass.dispatch_workgroups(dispatch, dispatch, dispatch);
         }
         Ok(())
     }
@@ -0,0 +1,66 @@
+use bevy::prelude::*;
+
+use crate::{
+    dig::terrain::spawn_terrain,
+    generation::{ComputePipelineReady, PendingChunkMeshes},
+    voxel::VoxelChunk,
+};
+
+/// The steps the game goes through before it can be played.
+#[derive(States, Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LoadingState {
+    /// Waiting for the marching cubes compute pipeline to be ready.
+    #[default]
+    CompilingPipeline,
+    /// The terrain is spawned and its first meshes are being generated.
+    Generating,
+    /// Everything is loaded, gameplay can start.
+    Ready,
+}
+
+/// How many chunks still need their first mesh while [`LoadingState::Generating`].
+#[derive(Resource, Default, Debug)]
+pub struct LoadingProgress {
+    pub remaining_chunks: usize,
+}
+
+pub struct LoadingPlugin;
+impl Plugin for LoadingPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_state::<LoadingState>()
+            .init_resource::<LoadingProgress>()
+            .add_systems(OnEnter(LoadingState::Generating), spawn_terrain)
+            .add_systems(
+                Update,
+                (
+                    wait_for_pipeline.run_if(in_state(LoadingState::CompilingPipeline)),
+                    wait_for_generation.run_if(in_state(LoadingState::Generating)),
+                ),
+            );
+    }
+}
+
+fn wait_for_pipeline(
+    pipeline_ready: Res<ComputePipelineReady>,
+    mut next_state: ResMut<NextState<LoadingState>>,
+) {
+    if pipeline_ready.is_ready() {
+        next_state.set(LoadingState::Generating);
+    }
+}
+
+fn wait_for_generation(
+    chunks_q: Query<Ref<VoxelChunk>>,
+    pending: PendingChunkMeshes,
+    mut progress: ResMut<LoadingProgress>,
+    mut next_state: ResMut<NextState<LoadingState>>,
+) {
+    // Chunks are only queued for generation once they have been spawned and noticed as changed
+    if chunks_q.is_empty() || chunks_q.iter().any(|chunk| chunk.is_changed()) {
+        return;
+    }
+    progress.remaining_chunks = pending.count();
+    if progress.remaining_chunks == 0 {
+        next_state.set(LoadingState::Ready);
+    }
+}
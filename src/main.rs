@@ -1,22 +1,23 @@
 use avian3d::{prelude::SleepingPlugin, PhysicsPlugins};
 use bevy::{
-    core::FrameCount,
     dev_tools::fps_overlay::{FpsOverlayConfig, FpsOverlayPlugin},
     prelude::*,
     text::FontSmoothing,
 };
 use bevy_editor_cam::{prelude::EditorCam, DefaultEditorCamPlugins};
 use dig::{
+    input::InputSource,
     player::spawn_player,
-    terrain::{spawn_terrain, FinishedGenerating},
+    terrain::{Aquifer, Aquifers},
     DigPlugin,
 };
 use indexed_camera::{IndexedCamera, IndexedCameraPlugin};
-use voxel::chunks_manager::ChunksManager;
+use loading::{LoadingPlugin, LoadingState};
 
 mod dig;
 mod generation;
 mod indexed_camera;
+mod loading;
 mod sky;
 mod voxel;
 
@@ -42,17 +43,20 @@ fn main() {
                 .disable::<SleepingPlugin>(),
             DefaultEditorCamPlugins,
             IndexedCameraPlugin,
-            DigPlugin,
+            DigPlugin::default(),
+            LoadingPlugin,
         ))
         .insert_resource(ClearColor(Color::BLACK))
+        // A pocket of water in the ground, flooding the tunnels dug into it
+        .insert_resource(Aquifers(vec![Aquifer {
+            center: IVec3::new(46, 72, 46),
+            radius: 8.,
+        }]))
         .add_systems(Startup, setup)
-        .add_systems(Update, (delayed_setup, handle_player_spawn))
+        .add_systems(OnEnter(LoadingState::Ready), setup_player)
         .run();
 }
 
-#[derive(Resource)]
-struct PlayerSpawned;
-
 fn setup(mut commands: Commands) {
     commands.spawn((
         DirectionalLight {
@@ -83,23 +87,17 @@ fn setup(mut commands: Commands) {
     ));
 }
 
-fn delayed_setup(chunks_manager: ChunksManager, frame_count: Res<FrameCount>) {
-    if frame_count.0 == 20 {
-        //Forced to delay creation by a delay because it doesn't work reliably otherwise
-        spawn_terrain(chunks_manager);
-    }
-}
-
-fn handle_player_spawn(
+fn setup_player(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
-    mut generation_r: EventReader<FinishedGenerating>,
-    maybe_player_spawned: Option<Res<PlayerSpawned>>,
 ) {
-    let read = generation_r.read();
-    if read.len() > 0 && maybe_player_spawned.is_none() {
-        commands.insert_resource(PlayerSpawned);
-        spawn_player(&mut commands, &mut meshes, &mut materials);
-    }
+    spawn_player(
+        &mut commands,
+        &mut meshes,
+        &mut materials,
+        InputSource::KeyboardMouse,
+        0,
+        Vec3::new(0., 1.5, 0.),
+    );
 }
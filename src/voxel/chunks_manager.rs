@@ -5,14 +5,15 @@ use bevy::{
         Vec3A,
     },
     prelude::*,
+    utils::{HashMap, HashSet},
 };
 
 use crate::{
     dig::terrain::VOXEL_SCALE,
-    generation::{BUFFER_LEN_UNCOMPRESSED, CHUNK_DATA, CHUNK_WIDTH},
+    generation::{field::FIELD_PADDING, ChunkSettings},
 };
 
-use super::VoxelChunk;
+use super::{water::WaterChunk, VoxelChunk, VoxelKind, VoxelLayers};
 
 #[derive(SystemParam)]
 pub struct ChunksManager<'w, 's> {
@@ -22,54 +23,246 @@ pub struct ChunksManager<'w, 's> {
     chunks: Query<'w, 's, &'static mut VoxelChunk>,
     #[doc(hidden)]
     chunks_info: Option<Res<'w, ChunksInfo>>,
+    #[doc(hidden)]
+    settings: Res<'w, ChunkSettings>,
+}
+
+/// Read only access to the voxels of the chunks, for systems that only look at the terrain and
+/// can run alongside each other.
+#[derive(SystemParam)]
+pub struct ChunksReader<'w, 's> {
+    #[doc(hidden)]
+    chunks: Query<'w, 's, &'static VoxelChunk>,
+    #[doc(hidden)]
+    chunks_info: Option<Res<'w, ChunksInfo>>,
+    #[doc(hidden)]
+    settings: Res<'w, ChunkSettings>,
 }
 
 #[derive(Resource)]
 pub struct ChunksInfo {
     amount: UVec3,
+    /// Entity of every chunk, by index.
+    entities: HashMap<UVec3, Entity>,
+}
+
+impl ChunksInfo {
+    /// The entity holding the [`VoxelChunk`] and [`WaterChunk`] of the chunk at `index`.
+    pub fn chunk_entity(&self, index: UVec3) -> Option<Entity> {
+        self.entities.get(&index).copied()
+    }
+}
+
+/// Identifies an edit registered with the [`PendingEdits`], so its completion can be waited on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct EditTicket(u64);
+
+pub struct VoxelEdit {
+    /// Indices of the chunks the edit modified.
+    pub chunks: Vec<UVec3>,
+    /// How many voxels changed state.
+    pub voxels: usize,
+    /// Indices of the chunks whose meshes show the modified voxels, the modified chunks along
+    /// with the neighbors sampling them at their borders.
+    pub remesh: Vec<UVec3>,
+    /// Generation of each modified chunk right after the edit.
+    generations: Vec<u64>,
+}
+
+/// Edits waiting for the meshes of the chunks they modified, along with the chunk generation
+/// each mesh needs to be built from.
+#[derive(Resource, Default)]
+pub struct PendingEdits {
+    next_ticket: u64,
+    edits: HashMap<EditTicket, Vec<(UVec3, u64)>>,
+}
+
+impl PendingEdits {
+    /// Waits for the meshes of the chunks modified by `edit`, the returned ticket is applied once
+    /// every one of them has its new mesh and collider.
+    pub fn register(&mut self, edit: &VoxelEdit) -> EditTicket {
+        let ticket = EditTicket(self.next_ticket);
+        self.next_ticket += 1;
+        let chunks = edit
+            .chunks
+            .iter()
+            .copied()
+            .zip(edit.generations.iter().copied())
+            .collect();
+        self.edits.insert(ticket, chunks);
+        ticket
+    }
+
+    /// Notes that the chunk's mesh is now built from at least `generation`.
+    pub fn chunk_ready(&mut self, index: UVec3, generation: u64) {
+        for chunks in self.edits.values_mut() {
+            chunks.retain(|(chunk_index, wanted)| *chunk_index != index || generation < *wanted);
+        }
+    }
+
+    /// Stops waiting for the chunks that no longer `exist`, their meshes will never be built.
+    pub fn forget_missing(&mut self, exists: impl Fn(UVec3) -> bool) {
+        for chunks in self.edits.values_mut() {
+            chunks.retain(|(index, _)| exists(*index));
+        }
+    }
+
+    /// Removes and returns the edits whose chunks all have up to date meshes.
+    pub fn take_applied(&mut self) -> Vec<EditTicket> {
+        let applied: Vec<EditTicket> = self
+            .edits
+            .iter()
+            .filter(|(_, chunks)| chunks.is_empty())
+            .map(|(ticket, _)| *ticket)
+            .collect();
+        for ticket in applied.iter() {
+            self.edits.remove(ticket);
+        }
+        applied
+    }
+}
+
+/// Solid voxels that are no longer connected to the bottom layer of the world.
+pub struct VoxelIsland {
+    /// Positions of the voxels, in the voxel space of the world.
+    pub voxels: Vec<IVec3>,
+    pub min: IVec3,
+    pub max: IVec3,
+}
+
+/// Sent whenever voxels are filled or emptied by an edit of the [`ChunksManager`], with the box
+/// holding them in the voxel space of the world. Granular voxels moving on their own don't send it.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct VoxelsChanged {
+    pub min: IVec3,
+    pub max: IVec3,
 }
 
 impl<'w, 's> ChunksManager<'w, 's> {
-    pub fn create_chunks(&mut self, amount: UVec3, scale: f32) {
+    /// Creates a terrain of `amount` chunks, its voxels made of `layers` from the surface down.
+    pub fn create_chunks(&mut self, amount: UVec3, scale: f32, layers: &VoxelLayers) {
         if amount.x == 0 || amount.y == 0 || amount.z == 0 {
             panic!("Amount should be atleast 1 on all axis");
         }
-        self.commands.insert_resource(ChunksInfo { amount });
+        let mut entities = HashMap::new();
+        let width = self.settings.width as u32;
+        let surface = amount.y * width - 1;
         for x in 0..amount.x {
             for y in 0..amount.y {
                 for z in 0..amount.z {
                     let index = UVec3::new(x, y, z);
-                    self.commands.spawn((
-                        Transform::from_translation(index.as_vec3() * scale),
-                        VoxelChunk::full(index),
-                    ));
+                    let mut chunk = VoxelChunk::full(index, self.settings.width);
+                    for i in 0..chunk.raw().len() {
+                        let pos = chunk.get_pos(i);
+                        chunk.set_kind(pos, layers.kind_at_depth(surface - (y * width + pos.y)));
+                    }
+                    let entity = self
+                        .commands
+                        .spawn((
+                            Transform::from_translation(index.as_vec3() * scale),
+                            chunk,
+                            WaterChunk::empty(index, self.settings.width),
+                        ))
+                        .id();
+                    entities.insert(index, entity);
                 }
             }
         }
+        self.commands
+            .insert_resource(ChunksInfo { amount, entities });
     }
 
-    pub fn set_sphere(&mut self, world_pos: Vec3, radius: f32, state: bool) {
+    /// Sets every voxel within the sphere to `state`.
+    /// Register the returned edit with the [`PendingEdits`] to know when it is applied.
+    pub fn set_sphere(&mut self, world_pos: Vec3, radius: f32, state: bool) -> VoxelEdit {
         let voxel_pos = self.world_pos_to_voxel_pos(world_pos);
         let voxel_radius = Self::world_length_to_voxel_length(radius);
+        self.set_region(voxel_pos, voxel_radius, state, |offset| {
+            offset.length_squared() < voxel_radius * voxel_radius
+        })
+    }
+
+    /// Digs a crater whose edge is pushed in and out by up to `roughness` times the radius, so it
+    /// looks blasted rather than carved. The same `seed` always gives the same crater.
+    pub fn dig_crater(
+        &mut self,
+        world_pos: Vec3,
+        radius: f32,
+        roughness: f32,
+        seed: u32,
+    ) -> VoxelEdit {
+        let voxel_pos = self.world_pos_to_voxel_pos(world_pos);
+        let voxel_radius = Self::world_length_to_voxel_length(radius);
+        let phases = Vec3::new(
+            hash_to_unit(seed, 0),
+            hash_to_unit(seed, 1),
+            hash_to_unit(seed, 2),
+        ) * std::f32::consts::TAU;
+        let reach = voxel_radius * (1. + roughness);
+        self.set_region(voxel_pos, reach, false, |offset| {
+            let distance = offset.length();
+            // Broad bulges along the direction of the voxel, plus some crumbling of the edge
+            let direction = offset / distance.max(f32::EPSILON);
+            let waves = direction * 3. + phases;
+            let bulge = (waves.x.sin() + waves.y.sin() + waves.z.sin()) / 3.;
+            let crumble = hash_to_unit(seed ^ hash_position(offset.round().as_ivec3()), 3) - 0.5;
+            distance < voxel_radius * (1. + roughness * (bulge * 0.75 + crumble * 0.5))
+        })
+    }
+
+    /// Sets the voxels within `reach` of `voxel_pos` to `state`, for which `inside` returns true
+    /// given their offset from `voxel_pos`.
+    fn set_region(
+        &mut self,
+        voxel_pos: Vec3,
+        reach: f32,
+        state: bool,
+        inside: impl Fn(Vec3) -> bool,
+    ) -> VoxelEdit {
         let operation_bounds = Aabb3d {
-            min: (voxel_pos - Vec3::splat(voxel_radius)).into(),
-            max: (voxel_pos + Vec3::splat(voxel_radius)).into(),
+            min: (voxel_pos - Vec3::splat(reach)).into(),
+            max: (voxel_pos + Vec3::splat(reach)).into(),
         };
+        let chunk_width = self.settings.width as u32;
+        let mut affected = Vec::new();
+        let mut voxels = 0;
         for mut chunk in self.chunks.iter_mut() {
-            let chunk_min = (chunk.index * CHUNK_WIDTH as u32).as_vec3a();
+            let chunk_min = (chunk.index * chunk_width).as_vec3a();
             let chunk_bounds = Aabb3d {
                 min: chunk_min,
-                max: chunk_min + (UVec3::splat(CHUNK_WIDTH as u32 - 1)).as_vec3a(),
+                max: chunk_min + (UVec3::splat(chunk_width - 1)).as_vec3a(),
             };
             if operation_bounds.intersects(&chunk_bounds) {
                 let localized_pos = voxel_pos - <Vec3A as Into<Vec3>>::into(chunk_min);
-                chunk.set_sphere(localized_pos, voxel_radius, state);
+                voxels += chunk.set_where(state, |local| inside(local.as_vec3() - localized_pos));
+                affected.push((chunk.index, chunk.generation()));
             }
         }
+        let min = (voxel_pos - reach).floor().as_ivec3();
+        let max = (voxel_pos + reach).ceil().as_ivec3();
+        if voxels > 0 {
+            self.commands.send_event(VoxelsChanged { min, max });
+        }
+        let (chunks, generations) = affected.into_iter().unzip();
+        VoxelEdit {
+            chunks,
+            voxels,
+            remesh: self.chunks_showing(min, max).collect(),
+            generations,
+        }
+    }
+
+    /// Read only access to the chunks, with the same lookups as a [`ChunksReader`].
+    pub fn reader(&self) -> ChunksReader<'_, 's> {
+        ChunksReader {
+            chunks: self.chunks.to_readonly(),
+            chunks_info: self.chunks_info.as_ref().map(Res::clone),
+            settings: Res::clone(&self.settings),
+        }
     }
 
     pub fn get_amount(&self) -> UVec3 {
-        self.chunks_info.as_ref().map_or(UVec3::ZERO, |i| i.amount)
+        self.reader().get_amount()
     }
 
     pub fn get_chunk(&self, entity: Entity) -> &VoxelChunk {
@@ -77,81 +270,375 @@ impl<'w, 's> ChunksManager<'w, 's> {
     }
 
     pub fn get_chunk_by_index(&self, index: UVec3) -> Option<&VoxelChunk> {
-        self.chunks.iter().find(|c| c.index == index)
-    }
-
-    pub fn get_chunk_and_surrounding_data(&self, index: UVec3) -> [bool; CHUNK_DATA * 27] {
-        let mut result = [false; CHUNK_DATA * 27];
-        for z in 0..3 {
-            for y in 0..3 {
-                for x in 0..3 {
-                    let mut data = [false; CHUNK_DATA];
-                    if let Ok(offset_index) =
-                        (index.as_ivec3() + IVec3::new(x - 1, y - 1, z - 1)).try_into()
-                    {
-                        if let Some(chunk) = self.get_chunk_by_index(offset_index) {
-                            data = chunk.raw();
-                        }
-                    }
-                    for z2 in 0..CHUNK_WIDTH {
-                        for y2 in 0..CHUNK_WIDTH {
-                            for x2 in 0..CHUNK_WIDTH {
-                                let write_pos = (x2 + x as usize * CHUNK_WIDTH)
-                                    + (y2 + y as usize * CHUNK_WIDTH) * CHUNK_WIDTH * 3
-                                    + (z2 + z as usize * CHUNK_WIDTH)
-                                        * CHUNK_WIDTH
-                                        * 3
-                                        * CHUNK_WIDTH
-                                        * 3;
-                                let read_pos =
-                                    x2 + y2 * CHUNK_WIDTH + z2 * CHUNK_WIDTH * CHUNK_WIDTH;
-                                result[write_pos] = data[read_pos]
-                            }
-                        }
-                    }
+        self.reader().get_chunk_by_index(index)
+    }
+
+    /// Whether the voxel at the given position, in the voxel space of the whole world, is solid.
+    /// Voxels outside of every chunk are considered empty.
+    pub fn is_solid(&self, voxel_pos: IVec3) -> bool {
+        self.reader().is_solid(voxel_pos)
+    }
+
+    pub fn chunk_width(&self) -> usize {
+        self.settings.width
+    }
+
+    /// The kind of the voxel at the given position in the voxel space of the world, `None` if it is
+    /// empty or outside of every chunk.
+    pub fn voxel_kind(&self, voxel_pos: IVec3) -> Option<VoxelKind> {
+        self.reader().voxel_kind(voxel_pos)
+    }
+
+    /// Whether the position, in the voxel space of the world, is within the chunks and empty.
+    pub fn is_free(&self, voxel_pos: IVec3) -> bool {
+        let size = (self.get_amount() * self.settings.width as u32).as_ivec3();
+        voxel_pos.cmpge(IVec3::ZERO).all()
+            && voxel_pos.cmplt(size).all()
+            && !self.is_solid(voxel_pos)
+    }
+
+    /// Moves a solid voxel along with its kind, positions are in the voxel space of the world.
+    pub fn move_voxel(&mut self, from: IVec3, to: IVec3) {
+        let Some(kind) = self.voxel_kind(from) else {
+            return;
+        };
+        let chunk_width = IVec3::splat(self.settings.width as i32);
+        for (pos, value) in [(from, false), (to, true)] {
+            let Ok(index) = UVec3::try_from(pos.div_euclid(chunk_width)) else {
+                continue;
+            };
+            let Some(entity) = self
+                .chunks_info
+                .as_ref()
+                .and_then(|info| info.entities.get(&index))
+            else {
+                continue;
+            };
+            let Ok(mut chunk) = self.chunks.get_mut(*entity) else {
+                continue;
+            };
+            let local = pos.rem_euclid(chunk_width).as_uvec3();
+            chunk.set_voxel(local, value);
+            chunk.set_kind(local, kind);
+        }
+    }
+
+    /// Finds the groups of solid voxels touching the `seeds` that are no longer connected to the
+    /// bottom layer of the world, which anchors the terrain.
+    /// Granular voxels are left to fall on their own, so they neither hold nor belong to islands.
+    /// The search follows the voxels across chunks, but groups spanning `max_width` voxels or more
+    /// on any axis, or holding more than `max_voxels`, are considered anchored without looking
+    /// further, so overhangs bigger than that never break off.
+    pub fn find_islands(
+        &self,
+        seeds: impl IntoIterator<Item = IVec3>,
+        max_width: i32,
+        max_voxels: usize,
+    ) -> Vec<VoxelIsland> {
+        find_islands(seeds, max_width, max_voxels, |pos| {
+            self.voxel_kind(pos)
+                .is_some_and(|kind| kind.repose_drop().is_none())
+        })
+    }
+
+    /// Empties the given voxels, positioned in the voxel space of the world, returning the indices
+    /// of the chunks whose meshes show them, see [`VoxelEdit::remesh`].
+    pub fn remove_voxels(&mut self, voxels: &[IVec3]) -> Vec<UVec3> {
+        let chunk_width = IVec3::splat(self.settings.width as i32);
+        let mut by_chunk: HashMap<UVec3, Vec<UVec3>> = HashMap::new();
+        let mut remesh = HashSet::new();
+        for voxel in voxels {
+            remesh.extend(self.chunks_showing(*voxel, *voxel));
+            let Ok(index) = UVec3::try_from(voxel.div_euclid(chunk_width)) else {
+                continue;
+            };
+            by_chunk
+                .entry(index)
+                .or_default()
+                .push(voxel.rem_euclid(chunk_width).as_uvec3());
+        }
+        for mut chunk in self.chunks.iter_mut() {
+            if let Some(positions) = by_chunk.get(&chunk.index) {
+                for pos in positions {
+                    chunk.set_voxel(*pos, false);
                 }
             }
         }
-        result
+        remesh.into_iter().collect()
+    }
+
+    /// Indices of the existing chunks whose meshes sample voxels of the box from `min` to `max`,
+    /// in the voxel space of the world. Meshes look `FIELD_PADDING` voxels into their neighbors.
+    fn chunks_showing(&self, min: IVec3, max: IVec3) -> impl Iterator<Item = UVec3> {
+        let chunk_width = IVec3::splat(self.settings.width as i32);
+        let last = self.get_amount().as_ivec3() - 1;
+        let padding = IVec3::splat(FIELD_PADDING as i32);
+        let min = (min - padding).div_euclid(chunk_width).max(IVec3::ZERO);
+        let max = (max + padding).div_euclid(chunk_width).min(last);
+        (min.z..=max.z).flat_map(move |z| {
+            (min.y..=max.y).flat_map(move |y| {
+                (min.x..=max.x).map(move |x| UVec3::new(x as u32, y as u32, z as u32))
+            })
+        })
+    }
+
+    pub fn dig_sphere(&mut self, world_pos: Vec3, radius: f32) -> VoxelEdit {
+        self.set_sphere(world_pos, radius, false)
     }
 
-    pub fn get_chunk_surrounded(&self, index: UVec3) -> [bool; BUFFER_LEN_UNCOMPRESSED] {
-        let data = self.get_chunk_and_surrounding_data(index);
-        let mut result = [false; BUFFER_LEN_UNCOMPRESSED];
-        for x in 0..(CHUNK_WIDTH + 2) {
-            for y in 0..(CHUNK_WIDTH + 2) {
-                for z in 0..(CHUNK_WIDTH + 2) {
-                    let offset = UVec3::splat(CHUNK_WIDTH as u32 - 1)
-                        + UVec3::new(x as u32, y as u32, z as u32);
-                    let read_pos = (offset.x
-                        + offset.y * CHUNK_WIDTH as u32 * 3
-                        + offset.z * (CHUNK_WIDTH * 3 * CHUNK_WIDTH * 3) as u32)
-                        as usize;
-                    let write_pos =
-                        x + y * (CHUNK_WIDTH + 2) + z * (CHUNK_WIDTH + 2) * (CHUNK_WIDTH + 2);
-                    result[write_pos] = data[read_pos];
+    pub fn build_sphere(&mut self, world_pos: Vec3, radius: f32) -> VoxelEdit {
+        self.set_sphere(world_pos, radius, true)
+    }
+
+    pub fn world_pos_to_voxel_pos(&self, world_pos: Vec3) -> Vec3 {
+        self.reader().world_pos_to_voxel_pos(world_pos)
+    }
+
+    pub fn voxel_pos_to_world_pos(&self, voxel_pos: Vec3) -> Vec3 {
+        self.reader().voxel_pos_to_world_pos(voxel_pos)
+    }
+
+    pub fn world_length_to_voxel_length(world_length: f32) -> f32 {
+        world_length * (1. / VOXEL_SCALE)
+    }
+}
+
+impl<'w> ChunksReader<'w, '_> {
+    pub fn get_amount(&self) -> UVec3 {
+        self.chunks_info.as_ref().map_or(UVec3::ZERO, |i| i.amount)
+    }
+
+    pub fn get_chunk_by_index(&self, index: UVec3) -> Option<&'w VoxelChunk> {
+        let entity = self.chunk_entity(index)?;
+        self.chunks.get_inner(entity).ok()
+    }
+
+    /// The entity of the chunk at `index`, see [`ChunksInfo::chunk_entity`].
+    pub fn chunk_entity(&self, index: UVec3) -> Option<Entity> {
+        self.chunks_info.as_ref()?.chunk_entity(index)
+    }
+
+    /// Whether the voxel at the given position, in the voxel space of the whole world, is solid.
+    /// Voxels outside of every chunk are considered empty.
+    pub fn is_solid(&self, voxel_pos: IVec3) -> bool {
+        let chunk_width = IVec3::splat(self.settings.width as i32);
+        let Ok(index) = UVec3::try_from(voxel_pos.div_euclid(chunk_width)) else {
+            return false;
+        };
+        self.get_chunk_by_index(index)
+            .is_some_and(|chunk| chunk.get_voxel(voxel_pos.rem_euclid(chunk_width).as_uvec3()))
+    }
+
+    /// The kind of the voxel at the given position in the voxel space of the world, `None` if it is
+    /// empty or outside of every chunk.
+    pub fn voxel_kind(&self, voxel_pos: IVec3) -> Option<VoxelKind> {
+        let chunk_width = IVec3::splat(self.settings.width as i32);
+        let index = UVec3::try_from(voxel_pos.div_euclid(chunk_width)).ok()?;
+        self.get_chunk_by_index(index)?
+            .get_kind(voxel_pos.rem_euclid(chunk_width).as_uvec3())
+    }
+
+    pub fn chunk_width(&self) -> usize {
+        self.settings.width
+    }
+
+    pub fn settings(&self) -> &ChunkSettings {
+        &self.settings
+    }
+
+    /// Returns the chunk's voxels surrounded by a single voxel of its neighbors, as expected by the
+    /// compute shader.
+    pub fn get_chunk_surrounded(&self, index: UVec3) -> Vec<bool> {
+        self.get_chunk_padded(index, 1)
+    }
+
+    /// Returns the chunk's voxels surrounded by `padding` voxels taken from its neighbors.
+    /// Missing neighbors are considered empty.
+    pub fn get_chunk_padded(&self, index: UVec3, padding: usize) -> Vec<bool> {
+        let chunk_width = self.settings.width;
+        assert!(
+            padding <= chunk_width,
+            "Padding can't exceed the direct neighbors"
+        );
+        let neighbors: Vec<Option<&VoxelChunk>> = (0..27)
+            .map(|i| {
+                let offset = IVec3::new(i % 3 - 1, (i / 3) % 3 - 1, i / 9 - 1);
+                (index.as_ivec3() + offset)
+                    .try_into()
+                    .ok()
+                    .and_then(|offset_index| self.get_chunk_by_index(offset_index))
+            })
+            .collect();
+        let width = chunk_width + padding * 2;
+        let mut result = vec![false; width * width * width];
+        for z in 0..width {
+            for y in 0..width {
+                for x in 0..width {
+                    let local = IVec3::new(x as i32, y as i32, z as i32) - padding as i32;
+                    let chunk_offset = local.div_euclid(IVec3::splat(chunk_width as i32)) + 1;
+                    let Some(chunk) = neighbors
+                        [(chunk_offset.x + chunk_offset.y * 3 + chunk_offset.z * 9) as usize]
+                    else {
+                        continue;
+                    };
+                    let chunk_pos = local.rem_euclid(IVec3::splat(chunk_width as i32));
+                    result[x + y * width + z * width * width] =
+                        chunk.get_voxel(chunk_pos.as_uvec3());
                 }
             }
         }
         result
     }
 
-    pub fn dig_sphere(&mut self, world_pos: Vec3, radius: f32) {
-        self.set_sphere(world_pos, radius, false);
+    pub fn world_pos_to_voxel_pos(&self, world_pos: Vec3) -> Vec3 {
+        let voxel_pos_no_offset = world_pos * (1. / VOXEL_SCALE);
+        self.middle_offset() + voxel_pos_no_offset + Vec3::splat(-1.)
     }
 
-    pub fn build_sphere(&mut self, world_pos: Vec3, radius: f32) {
-        self.set_sphere(world_pos, radius, true);
+    pub fn voxel_pos_to_world_pos(&self, voxel_pos: Vec3) -> Vec3 {
+        (voxel_pos + Vec3::ONE - self.middle_offset()) * VOXEL_SCALE
     }
 
-    fn world_pos_to_voxel_pos(&self, world_pos: Vec3) -> Vec3 {
-        let mut middle_offset = self.get_amount().as_vec3() * CHUNK_WIDTH as f32 / 2.;
+    fn middle_offset(&self) -> Vec3 {
+        let mut middle_offset = self.get_amount().as_vec3() * self.settings.width as f32 / 2.;
         middle_offset.y *= 2.;
-        let voxel_pos_no_offset = world_pos * (1. / VOXEL_SCALE);
-        middle_offset + voxel_pos_no_offset + Vec3::splat(-1.)
+        middle_offset
     }
+}
 
-    fn world_length_to_voxel_length(world_length: f32) -> f32 {
-        world_length * (1. / VOXEL_SCALE)
+/// Flood fills the groups of voxels for which `is_solid` returns true from every seed, returning
+/// the ones that don't reach the bottom layer of the world, see [`ChunksManager::find_islands`].
+fn find_islands(
+    seeds: impl IntoIterator<Item = IVec3>,
+    max_width: i32,
+    max_voxels: usize,
+    is_solid: impl Fn(IVec3) -> bool,
+) -> Vec<VoxelIsland> {
+    // Voxels whose group is already known, anchored or not
+    let mut settled: HashSet<IVec3> = HashSet::new();
+    let mut islands = Vec::new();
+    for seed in seeds {
+        if settled.contains(&seed) || !is_solid(seed) {
+            continue;
+        }
+        let mut visited = HashSet::from([seed]);
+        let mut stack = vec![seed];
+        let mut island = VoxelIsland {
+            voxels: vec![seed],
+            min: seed,
+            max: seed,
+        };
+        let mut anchored = false;
+        while let Some(pos) = stack.pop() {
+            // Islands are always fully explored, so reaching a settled voxel means reaching an
+            // anchored group
+            if pos.y == 0 || settled.contains(&pos) {
+                anchored = true;
+                break;
+            }
+            // Going down last explores it first, which reaches the bottom of the world sooner
+            for direction in [
+                IVec3::X,
+                IVec3::NEG_X,
+                IVec3::Z,
+                IVec3::NEG_Z,
+                IVec3::Y,
+                IVec3::NEG_Y,
+            ] {
+                let next = pos + direction;
+                if visited.contains(&next) || !is_solid(next) {
+                    continue;
+                }
+                visited.insert(next);
+                stack.push(next);
+                island.voxels.push(next);
+                island.min = island.min.min(next);
+                island.max = island.max.max(next);
+            }
+            if island.voxels.len() > max_voxels
+                || (island.max - island.min)
+                    .cmpge(IVec3::splat(max_width))
+                    .any()
+            {
+                anchored = true;
+                break;
+            }
+        }
+        settled.extend(island.voxels.iter().copied());
+        if !anchored {
+            islands.push(island);
+        }
+    }
+    islands
+}
+
+/// Mixes the bits of a position into a single value.
+fn hash_position(pos: IVec3) -> u32 {
+    (pos.x as u32).wrapping_mul(0x8da6_b343)
+        ^ (pos.y as u32).wrapping_mul(0xd816_3841)
+        ^ (pos.z as u32).wrapping_mul(0xcb1a_b31f)
+}
+
+/// A pseudo random value in `0..1` for the given seed and channel.
+fn hash_to_unit(seed: u32, channel: u32) -> f32 {
+    let mut x = seed ^ channel.wrapping_mul(0x9e37_79b9);
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb_352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846c_a68b);
+    x ^= x >> 16;
+    (x >> 8) as f32 / (1 << 24) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::{prelude::*, utils::HashSet};
+
+    use super::find_islands;
+
+    fn islands_of(solid: &HashSet<IVec3>, max_width: i32, max_voxels: usize) -> Vec<Vec<IVec3>> {
+        find_islands(solid.iter().copied(), max_width, max_voxels, |pos| {
+            solid.contains(&pos)
+        })
+        .into_iter()
+        .map(|island| {
+            let mut voxels = island.voxels;
+            voxels.sort_by_key(|voxel| (voxel.x, voxel.y, voxel.z));
+            voxels
+        })
+        .collect()
+    }
+
+    #[test]
+    fn groups_reaching_the_bottom_layer_are_anchored() {
+        let solid: HashSet<IVec3> = (0..5)
+            .map(|y| IVec3::new(0, y, 0))
+            .chain((0..4).map(|x| IVec3::new(x, 4, 0)))
+            .collect();
+        assert!(islands_of(&solid, 64, 1000).is_empty());
+    }
+
+    #[test]
+    fn floating_groups_are_islands() {
+        let solid: HashSet<IVec3> = [
+            IVec3::new(0, 0, 0),
+            IVec3::new(0, 1, 0),
+            IVec3::new(0, 3, 0),
+            IVec3::new(1, 3, 0),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(
+            islands_of(&solid, 64, 1000),
+            vec![vec![IVec3::new(0, 3, 0), IVec3::new(1, 3, 0)]]
+        );
+    }
+
+    #[test]
+    fn groups_over_the_limits_are_anchored() {
+        let solid: HashSet<IVec3> = (0..10).map(|x| IVec3::new(x, 5, 0)).collect();
+        assert_eq!(islands_of(&solid, 64, 1000).len(), 1);
+        assert!(islands_of(&solid, 8, 1000).is_empty());
+        assert!(islands_of(&solid, 64, 4).is_empty());
     }
 }
@@ -1,30 +1,87 @@
-use bevy::{math::FloatPow, prelude::*};
-
-use crate::generation::{CHUNK_DATA, CHUNK_WIDTH};
+use bevy::prelude::*;
 
 pub mod chunks_manager;
+pub mod water;
+
+/// What a solid voxel is made of.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Reflect)]
+pub enum VoxelKind {
+    #[default]
+    Rock,
+    Sand,
+    Gravel,
+}
+
+/// Layers of voxels the terrain is created with, from the surface down, as a kind and how many
+/// voxels thick it is. Everything below the last layer is rock.
+/// Empty by default, so the terrain is all rock unless layers are added.
+#[derive(Resource, Clone, Debug, Default, Reflect)]
+#[reflect(Resource)]
+pub struct VoxelLayers(pub Vec<(VoxelKind, u32)>);
+
+impl VoxelLayers {
+    /// The kind of the voxels `depth` voxels below the surface of the terrain.
+    pub fn kind_at_depth(&self, depth: u32) -> VoxelKind {
+        let mut top = 0;
+        for &(kind, thickness) in self.0.iter() {
+            top += thickness;
+            if depth < top {
+                return kind;
+            }
+        }
+        VoxelKind::Rock
+    }
+}
+
+impl VoxelKind {
+    /// For granular kinds, how far the ground next to a voxel has to drop before the voxel slides
+    /// down to it. Piles settle with slopes of that many voxels down for each voxel across.
+    pub fn repose_drop(&self) -> Option<u32> {
+        match self {
+            VoxelKind::Rock => None,
+            VoxelKind::Sand => Some(1),
+            VoxelKind::Gravel => Some(2),
+        }
+    }
+}
 
 #[derive(Component, Debug)]
 pub struct VoxelChunk {
     pub index: UVec3,
-    voxels: [bool; CHUNK_DATA as usize],
+    width: usize,
+    voxels: Vec<bool>,
+    /// Only meaningful for solid voxels.
+    kinds: Vec<VoxelKind>,
+    generation: u64,
 }
 
 impl VoxelChunk {
-    pub fn new(index: UVec3, voxels: [bool; CHUNK_DATA as usize]) -> VoxelChunk {
-        VoxelChunk { index, voxels }
+    pub fn new(index: UVec3, width: usize, voxels: Vec<bool>) -> VoxelChunk {
+        assert_eq!(voxels.len(), width * width * width);
+        VoxelChunk {
+            index,
+            width,
+            kinds: vec![VoxelKind::default(); voxels.len()],
+            voxels,
+            generation: 0,
+        }
+    }
+
+    pub fn full(index: UVec3, width: usize) -> VoxelChunk {
+        VoxelChunk::new(index, width, vec![true; width * width * width])
     }
 
-    pub fn full(index: UVec3) -> VoxelChunk {
-        VoxelChunk::new(index, [true; CHUNK_DATA as usize])
+    pub fn raw(&self) -> &[bool] {
+        &self.voxels
     }
 
-    pub fn raw(&self) -> [bool; CHUNK_DATA as usize] {
-        self.voxels
+    /// Incremented every time the voxels are modified.
+    pub fn generation(&self) -> u64 {
+        self.generation
     }
 
     pub fn get_chunk_width(&self) -> usize {
-        CHUNK_WIDTH
+        self.width
     }
 
     pub fn get_index(&self, pos: UVec3) -> usize {
@@ -41,18 +98,42 @@ impl VoxelChunk {
         )
     }
 
+    pub fn get_voxel(&self, pos: UVec3) -> bool {
+        self.voxels[self.get_index(pos)]
+    }
+
+    /// Newly filled voxels are made of [`VoxelKind::Rock`].
     pub fn set_voxel(&mut self, pos: UVec3, value: bool) {
-        self.voxels[self.get_index(pos)] = value;
+        let index = self.get_index(pos);
+        if value && !self.voxels[index] {
+            self.kinds[index] = VoxelKind::default();
+        }
+        self.voxels[index] = value;
+        self.generation += 1;
+    }
+
+    /// The kind of the voxel, `None` if it is empty.
+    pub fn get_kind(&self, pos: UVec3) -> Option<VoxelKind> {
+        let index = self.get_index(pos);
+        self.voxels[index].then(|| self.kinds[index])
+    }
+
+    pub fn set_kind(&mut self, pos: UVec3, kind: VoxelKind) {
+        let index = self.get_index(pos);
+        self.kinds[index] = kind;
     }
 
-    pub fn set_sphere(&mut self, pos: Vec3, size: f32, state: bool) {
-        let size_squared = size.squared();
+    /// Sets every voxel for which `inside` returns true to `state`, returning how many changed.
+    pub fn set_where(&mut self, state: bool, inside: impl Fn(UVec3) -> bool) -> usize {
+        let mut changed = 0;
         for i in 0..self.voxels.len() {
             let voxel_pos = self.get_pos(i);
 
-            if voxel_pos.as_vec3().distance_squared(pos) < size_squared {
+            if self.voxels[i] != state && inside(voxel_pos) {
                 self.set_voxel(voxel_pos, state);
+                changed += 1;
             }
         }
+        changed
     }
 }
@@ -0,0 +1,42 @@
+use bevy::prelude::*;
+
+/// Fill level of a voxel completely filled with water.
+pub const WATER_FULL: u8 = u8::MAX;
+
+/// Water held by the empty voxels of a chunk, stored next to its [`VoxelChunk`](super::VoxelChunk)
+/// so that flowing water doesn't remesh the terrain.
+#[derive(Component, Debug)]
+pub struct WaterChunk {
+    pub index: UVec3,
+    width: usize,
+    levels: Vec<u8>,
+}
+
+impl WaterChunk {
+    pub fn empty(index: UVec3, width: usize) -> WaterChunk {
+        WaterChunk {
+            index,
+            width,
+            levels: vec![0; width * width * width],
+        }
+    }
+
+    fn get_index(&self, pos: UVec3) -> usize {
+        let width = self.width as u32;
+        (pos.x + pos.y * width + pos.z * width * width) as usize
+    }
+
+    pub fn get_level(&self, pos: UVec3) -> u8 {
+        self.levels[self.get_index(pos)]
+    }
+
+    pub fn set_level(&mut self, pos: UVec3, level: u8) {
+        let index = self.get_index(pos);
+        self.levels[index] = level;
+    }
+
+    /// Whether the chunk holds no water at all.
+    pub fn is_dry(&self) -> bool {
+        self.levels.iter().all(|level| *level == 0)
+    }
+}